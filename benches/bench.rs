@@ -3,6 +3,7 @@ use std::sync::{Arc, Mutex, RwLock};
 use std::cell::RefCell;
 
 use rcu_clean::{BoxRcu, RcRcu, ArcRcu};
+use rcu_clean::graceful::Rcu;
 
 use criterion::{Criterion, criterion_group, criterion_main};
 
@@ -38,6 +39,7 @@ macro_rules! benchme {
     }
 }
 
+#[allow(clippy::borrowed_box)]
 fn criterion_benchmark(c: &mut Criterion) {
     let mut funs: Vec<criterion::Fun<usize>> = Vec::new();
     funs.push(benchme!("BoxRefCell", BoxRefCell, |a| Box::new(RefCell::new(a)),
@@ -62,24 +64,55 @@ fn criterion_benchmark(c: &mut Criterion) {
 
     funs.push(benchme!("RcRcuStale", RcRcu,
                        |i| { let x = RcRcu::new(0); *x.update() = i; x },
-                       |x: &RcRcu<usize>| **x as usize));
+                       |x: &RcRcu<usize>| **x));
     funs.push(benchme!("ArcRcuStale", ArcRcu,
                        |i| { let x = ArcRcu::new(0); *x.update() = i; x },
-                       |x: &ArcRcu<usize>| **x as usize));
+                       |x: &ArcRcu<usize>| **x));
     funs.push(benchme!("BoxRcuStale", BoxRcu,
                        |i| { let x = BoxRcu::new(0); *x.update() = i; x },
-                       |x: &BoxRcu<usize>| **x as usize));
+                       |x: &BoxRcu<usize>| **x));
 
     funs.push(benchme!("RcRcu", RcRcu, RcRcu::new,
-                       |x: &RcRcu<usize>| **x as usize));
+                       |x: &RcRcu<usize>| **x));
     funs.push(benchme!("ArcRcu", ArcRcu, ArcRcu::new,
-                       |x: &ArcRcu<usize>| **x as usize));
+                       |x: &ArcRcu<usize>| **x));
     funs.push(benchme!("BoxRcu", BoxRcu, BoxRcu::new,
-                       |x: &BoxRcu<usize>| **x as usize));
+                       |x: &BoxRcu<usize>| **x));
 
     funs.reverse();
     c.bench_functions("sum", funs, 1000);
 }
 
-criterion_group!(benches, criterion_benchmark);
+macro_rules! benchupdate {
+    ($name:expr, $t:ident, $update:ident) => {
+        criterion::Fun::new($name, |b,&len| {
+            let mut x: $t<Vec<usize>> = $t::new(vec![0; len]);
+            b.iter(|| {
+                x.$update()[0] += 1;
+                x.clean();
+            });
+        })
+    }
+}
+
+fn update_benchmark(c: &mut Criterion) {
+    let mut funs: Vec<criterion::Fun<usize>> = Vec::new();
+    funs.push(benchupdate!("ArcRcu update", ArcRcu, update));
+    funs.push(benchupdate!("ArcRcu update_mut", ArcRcu, update_mut));
+    funs.push(benchupdate!("RcRcu update", RcRcu, update));
+    funs.push(benchupdate!("RcRcu update_mut", RcRcu, update_mut));
+    funs.push(benchupdate!("BoxRcu update", BoxRcu, update));
+    funs.push(benchupdate!("BoxRcu update_mut", BoxRcu, update_mut));
+    funs.push(criterion::Fun::new("Rcu update", |b,&len| {
+        let x = Rcu::new(vec![0usize; len]);
+        b.iter(|| x.update(|v| v[0] += 1));
+    }));
+    funs.push(criterion::Fun::new("Rcu update_mut", |b,&len| {
+        let mut x = Rcu::new(vec![0usize; len]);
+        b.iter(|| x.update_mut(|v| v[0] += 1));
+    }));
+    c.bench_functions("update large vec", funs, 100_000);
+}
+
+criterion_group!(benches, criterion_benchmark, update_benchmark);
 criterion_main!(benches);
@@ -1,7 +1,13 @@
+//! [ArcRcu], a thread-safe reference counted RCU pointer, and its update guards
 use std::cell::{Cell, UnsafeCell};
 use std::ptr::null_mut;
-use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::history::History;
+use crate::recycle::Recycler;
+use crate::{Conflict, RcuClone, Version};
 
 /// A thread-safe reference counted pointer that allows interior mutability
 ///
@@ -12,7 +18,6 @@ use std::sync::Arc;
 /// `Arc<RwLock<T>>` or a `Arc<Mutex<T>>`.  So in this case you gain
 /// both ergonomics and read speed.  Writes are slow, so only use this
 /// type if writes are rare (or their speed doesn't matter).
-
 /// ```
 /// let x = rcu_clean::ArcRcu::new(3);
 /// let y: &usize = &(*x);
@@ -22,13 +27,13 @@ use std::sync::Arc;
 /// assert_eq!(*x, 7); // but the pointer now points to the new value.
 /// assert_eq!(*z, 7); // but the cloned pointer also points to the new value.
 /// ```
-pub struct ArcRcu<T> {
+pub struct ArcRcu<T: ?Sized> {
     inner: Arc<Inner<T>>,
     have_borrowed: Cell<bool>,
 }
-unsafe impl<T: Send + Sync> Send for ArcRcu<T> {}
-unsafe impl<T: Send + Sync> Sync for ArcRcu<T> {}
-impl<T: Clone> Clone for ArcRcu<T> {
+unsafe impl<T: ?Sized + Send + Sync> Send for ArcRcu<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for ArcRcu<T> {}
+impl<T: ?Sized> Clone for ArcRcu<T> {
     fn clone(&self) -> Self {
         ArcRcu {
             inner: self.inner.clone(),
@@ -36,71 +41,346 @@ impl<T: Clone> Clone for ArcRcu<T> {
         }
     }
 }
-pub struct Inner<T> {
+pub(crate) struct Inner<T: ?Sized> {
     borrow_count: AtomicUsize,
     am_writing: AtomicBool,
+    // The number of threads blocked waiting for an update, so that
+    // publishing only needs to touch `wait_lock` when someone is waiting.
+    waiters: AtomicUsize,
+    wait_lock: Mutex<()>,
+    changed: Condvar,
+    history: Mutex<Option<Recorder<T>>>,
+    recycler: Mutex<Spares<T>>,
     list: List<T>,
 }
-pub struct List<T> {
-    value: UnsafeCell<T>,
+// Retired list entries kept for reuse, or the reclaimer that gets them
+type Spares<T> = Recycler<Box<List<T>>, fn(Box<T>)>;
+struct Recorder<T: ?Sized> {
+    history: History<T>,
+    // How to make a copy of a value for the history, which needs `T: RcuClone`
+    copy: fn(&T) -> Arc<T>,
+}
+// Each value is boxed so that `T` may be unsized while `List<T>` is not,
+// which is needed for the `AtomicPtr`.
+pub(crate) struct List<T: ?Sized> {
+    value: UnsafeCell<Box<T>>,
+    version: AtomicU64,
     next: AtomicPtr<List<T>>,
 }
+impl<T: ?Sized> List<T> {
+    fn new(value: Box<T>) -> Self {
+        List {
+            value: UnsafeCell::new(value),
+            version: AtomicU64::new(0),
+            next: AtomicPtr::new(null_mut()),
+        }
+    }
+    fn version(&self) -> Version {
+        Version(self.version.load(Ordering::Relaxed))
+    }
+    /// Free this entry, but not its value, which is returned
+    ///
+    /// This must only be called on an entry with no `next`.
+    fn into_value(self) -> Box<T> {
+        let list = std::mem::ManuallyDrop::new(self);
+        unsafe { std::ptr::read(list.value.get()) }
+    }
+}
 
-impl<T> std::ops::Deref for ArcRcu<T> {
+impl<T: ?Sized> Inner<T> {
+    fn new(value: Box<T>) -> Self {
+        Inner {
+            borrow_count: AtomicUsize::new(0),
+            am_writing: AtomicBool::new(false),
+            waiters: AtomicUsize::new(0),
+            wait_lock: Mutex::new(()),
+            changed: Condvar::new(),
+            history: Mutex::new(None),
+            recycler: Mutex::new(Recycler::new()),
+            list: List::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> std::ops::Deref for ArcRcu<T> {
     type Target = T;
     fn deref(&self) -> &T {
-        let aleady_borrowed = self.have_borrowed.get();
-        if !aleady_borrowed {
-            self.inner.borrow_count.fetch_add(1, Ordering::Relaxed);
-            self.have_borrowed.set(true); // indicate we have borrowed this once.
-        }
-        let next = self.inner.list.next.load(Ordering::Acquire);
-        if next == null_mut() {
-            unsafe { &*self.inner.list.value.get() }
-        } else {
-            unsafe { &*(*next).value.get() }
-        }
+        unsafe { &*self.borrow_list().value.get() }
     }
 }
-impl<T> std::borrow::Borrow<T> for ArcRcu<T> {
+impl<T: ?Sized> std::borrow::Borrow<T> for ArcRcu<T> {
     fn borrow(&self) -> &T {
-        &*self
+        self
     }
 }
-impl<T> Drop for List<T> {
+impl<T: ?Sized> Drop for List<T> {
     fn drop(&mut self) {
         let next = self.next.load(Ordering::Acquire);
-        if next != null_mut() {
+        if !next.is_null() {
             let _free_this = unsafe { Box::from_raw(next) };
         }
     }
 }
-impl<'a, T: Clone> ArcRcu<T> {
-    pub fn new(x: T) -> Self {
+impl<T: ?Sized> ArcRcu<T> {
+    /// Allocate a new pointer holding an already boxed value
+    ///
+    /// This allows `T` to be unsized, such as a `str`, a slice or a trait
+    /// object.
+    /// ```
+    /// let x: rcu_clean::ArcRcu<dyn std::fmt::Display + Send + Sync> =
+    ///     rcu_clean::ArcRcu::from_box(Box::new(3));
+    /// assert_eq!(x.to_string(), "3");
+    /// x.update_from(|_| Box::new("three"));
+    /// assert_eq!(x.to_string(), "three");
+    /// ```
+    pub fn from_box(x: Box<T>) -> Self {
         ArcRcu {
             have_borrowed: Cell::new(false),
-            inner: Arc::new(Inner {
-                borrow_count: AtomicUsize::new(0),
-                am_writing: AtomicBool::new(false),
-                list: List {
-                    value: UnsafeCell::new(x),
-                    next: AtomicPtr::new(null_mut()),
-                },
-            }),
+            inner: Arc::new(Inner::new(x)),
         }
     }
-    pub fn update(&'a self) -> Guard<'a, T> {
+    /// The list entry holding the newest value
+    fn newest(&self) -> &List<T> {
+        let next = self.inner.list.next.load(Ordering::Acquire);
+        if next.is_null() {
+            &self.inner.list
+        } else {
+            unsafe { &*next }
+        }
+    }
+    /// The list entry holding the newest value, noting that we have borrowed
+    fn borrow_list(&self) -> &List<T> {
+        let aleady_borrowed = self.have_borrowed.get();
+        if !aleady_borrowed {
+            self.inner.borrow_count.fetch_add(1, Ordering::Relaxed);
+            self.have_borrowed.set(true); // indicate we have borrowed this once.
+        }
+        self.newest()
+    }
+    /// The version of the current value
+    ///
+    /// Like any read, this counts as borrowing, since another clone could
+    /// otherwise `clean` away the list entry we are looking at.
+    pub fn version(&self) -> Version {
+        self.borrow_list().version()
+    }
+    /// Read the current value along with its version
+    /// ```
+    /// let x = rcu_clean::ArcRcu::new(1);
+    /// let (value, version) = x.read_versioned();
+    /// *x.update() = 2;
+    /// assert_eq!(*value, 1);
+    /// assert!(x.version() > version);
+    /// ```
+    pub fn read_versioned(&self) -> (&T, Version) {
+        let list = self.borrow_list();
+        (unsafe { &*list.value.get() }, list.version())
+    }
+    /// Create a [WeakArcRcu] pointer to this value
+    ///
+    /// This is an associated function that needs to be used as
+    /// `ArcRcu::downgrade(...)`, just like `Arc::downgrade`.
+    pub fn downgrade(this: &Self) -> WeakArcRcu<T> {
+        WeakArcRcu {
+            inner: Arc::downgrade(&this.inner),
+        }
+    }
+    /// Publish a new value computed from the current one
+    ///
+    /// Since your function creates an entirely new boxed value, this works
+    /// even when `T` is unsized and cannot be cloned.
+    /// ```
+    /// let x: rcu_clean::ArcRcu<str> = rcu_clean::ArcRcu::from_box("hello".into());
+    /// x.update_from(|s| format!("{} world", s).into());
+    /// assert_eq!(&*x, "hello world");
+    /// ```
+    pub fn update_from(&self, f: impl FnOnce(&T) -> Box<T>) {
+        let value = f(self);
         if self.inner.am_writing.swap(true, Ordering::Relaxed) {
             panic!("Cannont update an ArcRcu twice simultaneously.");
         }
-        Guard {
-            list: Some(List {
-                value: UnsafeCell::new((*(*self)).clone()),
-                next: AtomicPtr::new(self.inner.list.next.load(Ordering::Acquire)),
-            }),
-            rc_guts: &self.inner,
+        self.publish(self.new_list(value));
+        self.inner.am_writing.store(false, Ordering::Relaxed);
+    }
+    /// Keep up to `spares` retired values for reuse by later updates
+    ///
+    /// Normally each value is dropped once `clean` shows that no one can be
+    /// reading it.  With spares, we instead keep a few of those values, so
+    /// that later updates can reuse their allocations rather than making new
+    /// ones.  The spares are not dropped until they are reused or we are.
+    /// ```
+    /// let mut x = rcu_clean::ArcRcu::new(vec![0; 100]).with_spares(1);
+    /// let buffer = x.as_ptr();
+    /// x.update()[0] = 1;
+    /// x.clean();
+    /// x.update()[0] = 2;
+    /// assert_eq!(x.as_ptr(), buffer);
+    /// ```
+    pub fn with_spares(self, spares: usize) -> Self {
+        let extra = self.inner.recycler.lock().unwrap().set_spares(spares);
+        drop(extra);
+        self
+    }
+    /// Hand each value we retire to `reclaimer`, rather than dropping it
+    ///
+    /// A reclaimer gets every retired value (once `clean` shows that no one
+    /// can be reading it), even if we were asked to keep spares, so it can
+    /// recycle them however it likes, or send them to another thread to be
+    /// dropped.
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// static RECLAIMED: AtomicUsize = AtomicUsize::new(0);
+    /// let mut x = rcu_clean::ArcRcu::new(vec![0]).with_reclaimer(|v: Box<Vec<usize>>| {
+    ///     RECLAIMED.fetch_add(v.len(), Ordering::Relaxed);
+    /// });
+    /// x.update().push(1);
+    /// x.update().push(2);
+    /// x.clean();
+    /// assert_eq!(RECLAIMED.load(Ordering::Relaxed), 1 + 2);
+    /// ```
+    pub fn with_reclaimer(self, reclaimer: fn(Box<T>)) -> Self {
+        self.inner.recycler.lock().unwrap().set_reclaimer(reclaimer);
+        self
+    }
+    /// A list entry for a new value, reusing a retired one if we have one
+    fn new_list(&self, value: Box<T>) -> Box<List<T>> {
+        let spare = self.inner.recycler.lock().unwrap().take();
+        match spare {
+            Some(mut list) => {
+                *list.value.get_mut() = value;
+                list
+            }
+            None => Box::new(List::new(value)),
+        }
+    }
+    fn publish(&self, list: Box<List<T>>) -> Version {
+        let version = self.newest().version().next();
+        list.version.store(version.0, Ordering::Relaxed);
+        list.next.store(
+            self.inner.list.next.load(Ordering::Acquire),
+            Ordering::Relaxed,
+        );
+        self.inner
+            .list
+            .next
+            .store(Box::into_raw(list), Ordering::Release);
+        self.record_history();
+        // This fence pairs with the one in `wait_deadline`, so that either we
+        // see the waiter, or the waiter sees our new version.
+        fence(Ordering::SeqCst);
+        if self.inner.waiters.load(Ordering::Relaxed) > 0 {
+            let _lock = self.inner.wait_lock.lock().unwrap();
+            self.inner.changed.notify_all();
+        }
+        version
+    }
+    /// Add the newest value to our history, if we are keeping one
+    fn record_history(&self) {
+        if let Some(recorder) = self.inner.history.lock().unwrap().as_mut() {
+            let newest = self.newest();
+            let value = (recorder.copy)(unsafe { &*newest.value.get() });
+            recorder.history.record(newest.version(), value);
+        }
+    }
+    /// Block until a version newer than `since` is published
+    ///
+    /// This returns the new version right away if there already is one.
+    /// Readers are not slowed down by waiting threads, and publishing only
+    /// pays for notification when some thread is actually waiting.
+    /// ```
+    /// let x = rcu_clean::ArcRcu::new(1);
+    /// let since = x.version();
+    /// let y = x.clone();
+    /// let waiter = std::thread::spawn(move || {
+    ///     y.wait_for_update(since);
+    ///     *y
+    /// });
+    /// *x.update() = 2;
+    /// assert_eq!(waiter.join().unwrap(), 2);
+    /// ```
+    pub fn wait_for_update(&self, since: Version) -> Version {
+        self.wait_deadline(since, None).unwrap()
+    }
+    /// Block until a version newer than `since` is published, or we time out
+    ///
+    /// Returns `None` if no new version was published within `timeout`.
+    /// ```
+    /// let x = rcu_clean::ArcRcu::new(1);
+    /// let timeout = std::time::Duration::from_millis(1);
+    /// assert_eq!(x.wait_for_update_timeout(x.version(), timeout), None);
+    /// ```
+    pub fn wait_for_update_timeout(&self, since: Version, timeout: Duration) -> Option<Version> {
+        self.wait_deadline(since, Some(Instant::now() + timeout))
+    }
+    /// Block until the value satisfies `predicate`
+    ///
+    /// The predicate is checked against the current value, and then once
+    /// for each version that we see published.
+    /// ```
+    /// let x = rcu_clean::ArcRcu::new(0);
+    /// let y = x.clone();
+    /// let waiter = std::thread::spawn(move || *y.wait_until(|v| *v >= 3));
+    /// for i in 1..=3 {
+    ///     *x.update() = i;
+    /// }
+    /// assert_eq!(waiter.join().unwrap(), 3);
+    /// ```
+    pub fn wait_until(&self, mut predicate: impl FnMut(&T) -> bool) -> &T {
+        loop {
+            let (value, version) = self.read_versioned();
+            if predicate(value) {
+                return value;
+            }
+            self.wait_for_update(version);
+        }
+    }
+    /// Block until the value satisfies `predicate`, or we time out
+    ///
+    /// Returns `None` if the value did not satisfy `predicate` within
+    /// `timeout`.
+    pub fn wait_until_timeout(
+        &self,
+        mut predicate: impl FnMut(&T) -> bool,
+        timeout: Duration,
+    ) -> Option<&T> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let (value, version) = self.read_versioned();
+            if predicate(value) {
+                return Some(value);
+            }
+            self.wait_deadline(version, Some(deadline))?;
         }
     }
+    fn wait_deadline(&self, since: Version, deadline: Option<Instant>) -> Option<Version> {
+        self.inner.waiters.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        let mut lock = self.inner.wait_lock.lock().unwrap();
+        let result = loop {
+            let version = self.version();
+            if version > since {
+                break Some(version);
+            }
+            match deadline {
+                None => lock = self.inner.changed.wait(lock).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break None;
+                    }
+                    lock = self
+                        .inner
+                        .changed
+                        .wait_timeout(lock, deadline - now)
+                        .unwrap()
+                        .0;
+                }
+            }
+        };
+        drop(lock);
+        self.inner.waiters.fetch_sub(1, Ordering::Relaxed);
+        result
+    }
     pub fn clean(&mut self) {
         let aleady_borrowed = self.have_borrowed.get();
         if aleady_borrowed {
@@ -109,59 +389,379 @@ impl<'a, T: Clone> ArcRcu<T> {
         }
         let borrow_count = self.inner.borrow_count.load(Ordering::Relaxed);
         let next = self.inner.list.next.load(Ordering::Acquire);
-        if borrow_count == 0 && next != null_mut() {
+        if borrow_count == 0 && !next.is_null() {
             unsafe {
-                // make a copy of the old datum that we will need to free
-                let buffer: UnsafeCell<Option<T>> = UnsafeCell::new(None);
-                std::ptr::copy_nonoverlapping(
-                    self.inner.list.value.get(),
-                    buffer.get() as *mut T,
-                    1,
-                );
+                // hold onto the old datum that we will need to free
+                let old = std::ptr::read(self.inner.list.value.get());
                 // now copy the "good" value to the main spot
                 std::ptr::copy_nonoverlapping((*next).value.get(), self.inner.list.value.get(), 1);
+                self.inner
+                    .list
+                    .version
+                    .store((*next).version.load(Ordering::Relaxed), Ordering::Relaxed);
                 // Now we can set the pointer to null which activates
                 // the copy we just made.
-                let _to_be_freed =
-                    Box::from_raw(self.inner.list.next.swap(null_mut(), Ordering::Release));
-                std::ptr::copy_nonoverlapping(buffer.get() as *mut T, (*next).value.get(), 1);
-                let buffer_copy: UnsafeCell<Option<T>> = UnsafeCell::new(None);
-                std::ptr::copy_nonoverlapping(buffer_copy.get(), buffer.get(), 1);
+                let to_be_freed = self.inner.list.next.swap(null_mut(), Ordering::Release);
+                std::ptr::write((*to_be_freed).value.get(), old);
+                self.retire(to_be_freed);
+            }
+        }
+    }
+    /// Recycle a list of entries that no one can be reading any more
+    fn retire(&self, mut list: *mut List<T>) {
+        while !list.is_null() {
+            let old = unsafe { Box::from_raw(list) };
+            list = old.next.swap(null_mut(), Ordering::Acquire);
+            self.recycle(old);
+        }
+    }
+    /// Keep an entry that no one is reading for reuse, or reclaim it
+    fn recycle(&self, list: Box<List<T>>) {
+        let mut recycler = self.inner.recycler.lock().unwrap();
+        if let Some(list) = recycler.keep(list) {
+            let reclaimer = recycler.reclaimer();
+            drop(recycler);
+            match reclaimer {
+                Some(reclaim) => reclaim((*list).into_value()),
+                None => drop(list),
+            }
+        }
+    }
+}
+impl<'a, T: RcuClone> ArcRcu<T> {
+    pub fn new(x: T) -> Self {
+        ArcRcu::from_box(Box::new(x))
+    }
+    /// Allocate a new pointer to a value that refers to itself
+    ///
+    /// Like `Arc::new_cyclic`, this gives your closure a [WeakArcRcu] that
+    /// will point to the `ArcRcu` being created, but cannot be upgraded until
+    /// the closure has returned.
+    pub fn new_cyclic(f: impl FnOnce(&WeakArcRcu<T>) -> T) -> Self {
+        ArcRcu {
+            have_borrowed: Cell::new(false),
+            inner: Arc::new_cyclic(|weak| {
+                Inner::new(Box::new(f(&WeakArcRcu {
+                    inner: weak.clone(),
+                })))
+            }),
+        }
+    }
+    /// Obtain a guard for modifying the value
+    ///
+    /// The value is only copied when the guard is first dereferenced
+    /// mutably, and a guard that is never dereferenced mutably publishes
+    /// nothing when it is dropped.  The guard never modifies the value in
+    /// place, since a reference read through this same `&self` could be
+    /// alive while we write; see [ArcRcu::update_mut] for that.
+    pub fn update(&'a self) -> Guard<'a, T> {
+        if self.inner.am_writing.swap(true, Ordering::Relaxed) {
+            panic!("Cannont update an ArcRcu twice simultaneously.");
+        }
+        Guard {
+            value: None,
+            rcu: self,
+            same: None,
+            in_place: false,
+            modified_in_place: false,
+        }
+    }
+    /// Keep copies of the last `len` published values, so that we can revert
+    ///
+    /// Each value is copied into the history when it is published, so
+    /// history is only kept if you ask for it.  A `len` of zero stops
+    /// keeping history.  The history is shared by all clones of this pointer.
+    /// ```
+    /// let config = rcu_clean::ArcRcu::new("good");
+    /// config.keep_history(10);
+    /// *config.update() = "bad";
+    /// config.revert();
+    /// assert_eq!(*config, "good");
+    /// assert_eq!(config.history().count(), 3);
+    /// ```
+    pub fn keep_history(&self, len: usize) {
+        let mut recorder = self.inner.history.lock().unwrap();
+        if len == 0 {
+            *recorder = None;
+        } else if let Some(recorder) = recorder.as_mut() {
+            recorder.history.set_limit(len);
+        } else {
+            let mut history = History::new(len);
+            let (value, version) = self.read_versioned();
+            history.record(version, Arc::new(value.rcu_clone()));
+            *recorder = Some(Recorder {
+                history,
+                copy: |value| Arc::new(value.rcu_clone()),
+            });
+        }
+    }
+    /// The values in our history, newest first, along with their versions
+    ///
+    /// This is empty unless [ArcRcu::keep_history] has been called.
+    pub fn history(&self) -> impl Iterator<Item = (Version, Arc<T>)> {
+        let recorder = self.inner.history.lock().unwrap();
+        let entries = recorder.as_ref().map(|r| r.history.entries());
+        entries.unwrap_or_default().into_iter()
+    }
+    /// Undo the latest update, by republishing the value before it
+    ///
+    /// The old value is published as a new version, which is returned.  If
+    /// there is no earlier value in the history, nothing is published.
+    /// Reverting again undoes the update before that, and so on.
+    pub fn revert(&self) -> Option<Version> {
+        let recorder = self.inner.history.lock().unwrap();
+        let (restored, previous) = recorder.as_ref()?.history.previous()?;
+        drop(recorder);
+        Some(self.republish(&previous, restored))
+    }
+    /// Republish the value with the given version from our history
+    ///
+    /// The old value is published as a new version, which is returned.  If
+    /// `version` is not in the history, nothing is published.
+    /// ```
+    /// let x = rcu_clean::ArcRcu::new(0);
+    /// x.keep_history(3);
+    /// let first = x.version();
+    /// for i in 1..5 {
+    ///     *x.update() = i;
+    /// }
+    /// assert_eq!(x.revert_to(first), None); // forgotten
+    /// let (two, _) = x.history().find(|(_, v)| **v == 2).unwrap();
+    /// assert!(x.revert_to(two).is_some());
+    /// assert_eq!(*x, 2);
+    /// ```
+    pub fn revert_to(&self, version: Version) -> Option<Version> {
+        let recorder = self.inner.history.lock().unwrap();
+        let value = recorder.as_ref()?.history.get(version)?;
+        drop(recorder);
+        Some(self.republish(&value, version))
+    }
+    fn republish(&self, value: &T, restored: Version) -> Version {
+        if self.inner.am_writing.swap(true, Ordering::Relaxed) {
+            panic!("Cannont update an ArcRcu twice simultaneously.");
+        }
+        let version = self.publish(self.copy(value));
+        if let Some(recorder) = self.inner.history.lock().unwrap().as_mut() {
+            recorder.history.restored(version, restored);
+        }
+        self.inner.am_writing.store(false, Ordering::Relaxed);
+        version
+    }
+    /// A list entry holding a copy of `value`, reusing a retired one if we can
+    fn copy(&self, value: &T) -> Box<List<T>> {
+        let spare = self.inner.recycler.lock().unwrap().take();
+        match spare {
+            Some(mut list) => {
+                list.value.get_mut().rcu_clone_from(value);
+                list
             }
+            None => Box::new(List::new(Box::new(value.rcu_clone()))),
+        }
+    }
+    /// Modify the value only if it is still at the `expected` version
+    ///
+    /// This allows optimistic concurrency: read the value along with its
+    /// version, compute what you want to change, and then apply the change
+    /// only if no one else has published a new value in the meantime.
+    /// ```
+    /// let x = rcu_clean::ArcRcu::new(1);
+    /// let (_, version) = x.read_versioned();
+    /// assert!(x.update_if_version(version, |v| *v = 2).is_ok());
+    /// assert!(x.update_if_version(version, |v| *v = 3).is_err());
+    /// assert_eq!(*x, 2);
+    /// ```
+    pub fn update_if_version<R>(
+        &'a self,
+        expected: Version,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Result<R, Conflict> {
+        let mut guard = self.update();
+        // Now that we hold the guard, no one else can publish.
+        let actual = self.version();
+        if actual != expected {
+            return Err(Conflict { expected, actual });
         }
+        Ok(f(&mut guard))
+    }
+    /// Obtain a guard for modifying the value, in place if possible
+    ///
+    /// When this is the only `ArcRcu` pointing to the value, no reader can
+    /// observe the old value, so (much like `Arc::make_mut`) the guard
+    /// modifies the value in place rather than copying it.  Otherwise this
+    /// behaves just like [ArcRcu::update].  We need a `&mut self` to be
+    /// sure that no references have been handed out by this pointer.
+    /// ```
+    /// let mut x = rcu_clean::ArcRcu::new(vec![0; 1000]);
+    /// x.update_mut()[0] = 1; // No copy of the vec is made.
+    /// let y = x.clone();
+    /// x.update_mut()[0] = 2; // Here we copy, since `y` could be read.
+    /// assert_eq!(y[0], 2);
+    /// ```
+    pub fn update_mut(&'a mut self) -> Guard<'a, T> {
+        let mut in_place = Arc::get_mut(&mut self.inner).is_some();
+        if in_place {
+            self.clean();
+            // A clone that borrowed and was then dropped still counts as a
+            // borrower, so `clean` may have left newer values in the list,
+            // which we must not hide by writing to the old one.
+            in_place = self.inner.list.next.load(Ordering::Acquire).is_null();
+        }
+        let mut guard = self.update();
+        guard.in_place = in_place;
+        guard
+    }
+    /// Obtain a guard that only publishes the value if it has changed
+    ///
+    /// When the guard is dropped, the modified copy is compared with the
+    /// current value, and if they are equal the copy is discarded, so readers
+    /// need not follow an extra pointer to reach an identical value.
+    /// ```
+    /// let x = rcu_clean::ArcRcu::new(vec![1, 2]);
+    /// x.update_if_changed().sort();
+    /// assert_eq!(*x, vec![1, 2]);
+    /// ```
+    pub fn update_if_changed(&'a self) -> Guard<'a, T>
+    where
+        T: PartialEq,
+    {
+        let mut guard = self.update();
+        guard.same = Some(T::eq);
+        guard
+    }
+}
+
+/// A weak pointer to an [ArcRcu]
+///
+/// This is created by [ArcRcu::downgrade], and like `std::sync::Weak` it does
+/// not keep the value alive.  Upgrading gives an [ArcRcu] that sees the
+/// latest value published through any of its clones.
+/// ```
+/// let x = rcu_clean::ArcRcu::new(1);
+/// let weak = rcu_clean::ArcRcu::downgrade(&x);
+/// *x.update() = 2;
+/// assert_eq!(*weak.upgrade().unwrap(), 2);
+/// drop(x);
+/// assert!(weak.upgrade().is_none());
+/// ```
+pub struct WeakArcRcu<T: ?Sized> {
+    inner: std::sync::Weak<Inner<T>>,
+}
+unsafe impl<T: ?Sized + Send + Sync> Send for WeakArcRcu<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for WeakArcRcu<T> {}
+impl<T: ?Sized> WeakArcRcu<T> {
+    /// Create a [WeakArcRcu] that points to nothing
+    pub fn new() -> Self {
+        WeakArcRcu {
+            inner: std::sync::Weak::new(),
+        }
+    }
+    /// Attempt to get an [ArcRcu], if the value still exists
+    pub fn upgrade(&self) -> Option<ArcRcu<T>> {
+        self.inner.upgrade().map(|inner| ArcRcu {
+            inner,
+            have_borrowed: Cell::new(false),
+        })
+    }
+}
+impl<T: ?Sized> Clone for WeakArcRcu<T> {
+    fn clone(&self) -> Self {
+        WeakArcRcu {
+            inner: self.inner.clone(),
+        }
+    }
+}
+impl<T: ?Sized> Default for WeakArcRcu<T> {
+    fn default() -> Self {
+        WeakArcRcu::new()
     }
 }
 
-pub struct Guard<'a, T: Clone> {
-    list: Option<List<T>>,
-    rc_guts: &'a Inner<T>,
+pub struct Guard<'a, T: RcuClone> {
+    value: Option<Box<List<T>>>,
+    rcu: &'a ArcRcu<T>,
+    same: Option<fn(&T, &T) -> bool>,
+    in_place: bool,
+    modified_in_place: bool,
 }
-impl<'a, T: Clone> std::ops::Deref for Guard<'a, T> {
+impl<'a, T: RcuClone> std::ops::Deref for Guard<'a, T> {
     type Target = T;
     fn deref(&self) -> &T {
-        if let Some(ref list) = self.list {
+        if let Some(ref list) = self.value {
             unsafe { &*list.value.get() }
         } else {
-            unreachable!()
+            self.rcu
         }
     }
 }
-impl<'a, T: Clone> std::ops::DerefMut for Guard<'a, T> {
+impl<'a, T: RcuClone> std::ops::DerefMut for Guard<'a, T> {
     fn deref_mut(&mut self) -> &mut T {
-        if let Some(ref list) = self.list {
-            unsafe { &mut *list.value.get() }
-        } else {
-            unreachable!()
+        if self.in_place {
+            // We have a unique pointer that has been cleaned, so the value
+            // is stored in place.
+            self.modified_in_place = true;
+            return unsafe { &mut *self.rcu.inner.list.value.get() };
         }
+        let rcu = self.rcu;
+        let list = self.value.get_or_insert_with(|| rcu.copy(rcu));
+        list.value.get_mut()
     }
 }
-impl<'a, T: Clone> Drop for Guard<'a, T> {
+impl<'a, T: RcuClone> Drop for Guard<'a, T> {
     fn drop(&mut self) {
-        let list = std::mem::replace(&mut self.list, None);
-        self.rc_guts
-            .list
-            .next
-            .store(Box::into_raw(Box::new(list.unwrap())), Ordering::Release);
-        self.rc_guts.am_writing.store(false, Ordering::Relaxed);
+        if let Some(mut list) = self.value.take() {
+            let unchanged = match self.same {
+                Some(same) => same(list.value.get_mut(), self.rcu),
+                None => false,
+            };
+            if unchanged {
+                self.rcu.recycle(list);
+            } else {
+                self.rcu.publish(list);
+            }
+        }
+        if self.modified_in_place {
+            self.rcu.inner.list.version.fetch_add(1, Ordering::Relaxed);
+            self.rcu.record_history();
+        }
+        self.rcu.inner.am_writing.store(false, Ordering::Relaxed);
+    }
+}
+
+impl<'a, T: RcuClone> Guard<'a, T> {
+    /// Make a guard for a component of the value being updated
+    ///
+    /// This is an associated function that needs to be used as
+    /// `Guard::map(...)`, so that it does not interfere with methods of
+    /// `T`.  This has the same semantics as `std::cell::RefMut::map`.  The
+    /// entire updated value is published when the [MappedGuard] is dropped.
+    ///
+    /// ```
+    /// let x = rcu_clean::ArcRcu::new((1, 2));
+    /// {
+    ///     let update = x.update();
+    ///     let mut second = rcu_clean::arcrcu::Guard::map(update, |v| &mut v.1);
+    ///     *second = 7;
+    ///     assert_eq!(*x, (1, 2));
+    /// }
+    /// assert_eq!(*x, (1, 7));
+    /// ```
+    pub fn map<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(orig: Self, f: F) -> MappedGuard<'a, T, U> {
+        MappedGuard::new(orig, f)
+    }
+    /// Make a guard for an optional component of the value being updated
+    ///
+    /// This has the same semantics as `std::cell::RefMut::filter_map`.  If
+    /// the closure returns `None`, the original guard is handed back.
+    pub fn filter_map<U: ?Sized, F: FnOnce(&mut T) -> Option<&mut U>>(
+        orig: Self,
+        f: F,
+    ) -> Result<MappedGuard<'a, T, U>, Self> {
+        MappedGuard::filter_new(orig, f)
     }
 }
+
+/// A guard for a component of a value being updated
+///
+/// This is created by [Guard::map], and publishes the entire updated value
+/// when it is dropped.
+pub type MappedGuard<'a, T, U> = crate::MappedGuard<Guard<'a, T>, U>;
@@ -1,18 +1,20 @@
+//! [BoxRcu], an owned RCU pointer, and its update guards
 use std::ptr::null_mut;
 use std::sync::atomic::{AtomicPtr, Ordering};
 
+use crate::{Conflict, RcuClone, Version};
+
 /// An owned pointer that allows interior mutability
 ///
 /// An [BoxRcu] is currently the size of two pointers (plus the
 /// allocated data).  So one pointer of overhead versus a plain old
 /// `Box`.  You will probably want to to occasionally call `[clean]`
 /// to free up copies made when you call `update`.  Or you could just
-/// leak memory, that's cool too.
+/// let them pile up until the [BoxRcu] is dropped, that's cool too.
 ///
 /// Our benchmark oddly shows [BoxRcu] reads as being faster than
 /// reads using [Box].  I don't understand this, or particularly
 /// believe it.
-
 /// ```
 /// let x = rcu_clean::BoxRcu::new(3);
 /// let y: &usize = &(*x);
@@ -20,83 +22,317 @@ use std::sync::atomic::{AtomicPtr, Ordering};
 /// assert_eq!(*y, 3); // the old reference is still valid.
 /// assert_eq!(*x, 7); // but the pointer now points to the new value.
 /// ```
-pub struct BoxRcu<T> {
+/// Like a `Box`, a [BoxRcu] may only be sent to another thread if its value
+/// may be, and since any thread that shares it may publish values that the
+/// owner will drop, sharing it also needs the value to be `Send`.
+/// ```compile_fail
+/// fn assert_sync<T: Sync>(_: T) {}
+/// assert_sync(rcu_clean::BoxRcu::new(std::cell::Cell::new(0)));
+/// ```
+/// ```compile_fail
+/// fn assert_send<T: Send>(_: T) {}
+/// assert_send(rcu_clean::BoxRcu::new(std::rc::Rc::new(0)));
+/// ```
+pub struct BoxRcu<T: ?Sized> {
     inner: AtomicPtr<List<T>>,
 }
-pub struct List<T> {
-    value: T,
+// The `AtomicPtr` alone would make us `Send` and `Sync` for any `T`.
+unsafe impl<T: ?Sized + Send> Send for BoxRcu<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for BoxRcu<T> {}
+// Each value is boxed so that `T` may be unsized while `List<T>` is not,
+// which is needed for the `AtomicPtr`.
+pub(crate) struct List<T: ?Sized> {
+    value: Box<T>,
+    version: Version,
     next: AtomicPtr<List<T>>,
 }
 
-impl<T> std::ops::Deref for BoxRcu<T> {
+impl<T: ?Sized> std::ops::Deref for BoxRcu<T> {
     type Target = T;
     fn deref(&self) -> &T {
-        unsafe { &(*self.inner.load(Ordering::Acquire)).value }
+        &self.newest().value
     }
 }
-impl<T> std::borrow::Borrow<T> for BoxRcu<T> {
+impl<T: ?Sized> std::borrow::Borrow<T> for BoxRcu<T> {
     fn borrow(&self) -> &T {
-        &*self
+        self
     }
 }
-impl<T> Drop for List<T> {
+impl<T: ?Sized> Drop for BoxRcu<T> {
+    fn drop(&mut self) {
+        let _free_this = unsafe { Box::from_raw(*self.inner.get_mut()) };
+    }
+}
+impl<T: ?Sized> Drop for List<T> {
     fn drop(&mut self) {
         let next = self.next.load(Ordering::Acquire);
-        if next != null_mut() {
+        if !next.is_null() {
             let _free_this = unsafe { Box::from_raw(next) };
         }
     }
 }
-impl<'a, T: Clone> BoxRcu<T> {
-    pub fn new(x: T) -> Self {
+impl<T: ?Sized> BoxRcu<T> {
+    /// Allocate a new pointer holding an already boxed value
+    ///
+    /// This allows `T` to be unsized, such as a `str`, a slice or a trait
+    /// object.
+    /// ```
+    /// let x: rcu_clean::BoxRcu<dyn Fn(usize) -> usize> =
+    ///     rcu_clean::BoxRcu::from_box(Box::new(|x| x + 1));
+    /// assert_eq!(x(1), 2);
+    /// x.update_from(|_| Box::new(|x| x * 10));
+    /// assert_eq!(x(1), 10);
+    /// ```
+    pub fn from_box(x: Box<T>) -> Self {
         BoxRcu {
             inner: AtomicPtr::new(Box::into_raw(Box::new(List {
                 value: x,
+                version: Version(0),
                 next: AtomicPtr::new(null_mut()),
             }))),
         }
     }
-    pub fn update(&'a self) -> Guard<'a, T> {
-        Guard {
-            list: AtomicPtr::new(Box::into_raw(Box::new(List {
-                value: (*(*self)).clone(),
-                next: unsafe {
-                    AtomicPtr::new(
-                        (*self.inner.load(Ordering::Acquire))
-                            .next
-                            .load(Ordering::Acquire),
-                    )
-                },
-            }))),
-            thebox: &self,
+    fn newest(&self) -> &List<T> {
+        unsafe { &*self.inner.load(Ordering::Acquire) }
+    }
+    /// The version of the current value
+    pub fn version(&self) -> Version {
+        self.newest().version
+    }
+    /// Read the current value along with its version
+    /// ```
+    /// let x = rcu_clean::BoxRcu::new(1);
+    /// let (value, version) = x.read_versioned();
+    /// *x.update() = 2;
+    /// assert_eq!(*value, 1);
+    /// assert!(x.version() > version);
+    /// ```
+    pub fn read_versioned(&self) -> (&T, Version) {
+        let list = self.newest();
+        (&list.value, list.version)
+    }
+    /// Publish a new value computed from the current one
+    ///
+    /// Since your function creates an entirely new boxed value, this works
+    /// even when `T` is unsized and cannot be cloned.
+    pub fn update_from(&self, f: impl FnOnce(&T) -> Box<T>) {
+        self.publish(f(self));
+    }
+    fn publish(&self, value: Box<T>) -> Version {
+        let list = Self::new_list(value);
+        let mut old = self.inner.load(Ordering::Acquire);
+        loop {
+            match unsafe { self.try_publish(list, old) } {
+                Ok(version) => return version,
+                Err(current) => old = current,
+            }
         }
     }
+    fn new_list(value: Box<T>) -> *mut List<T> {
+        Box::into_raw(Box::new(List {
+            value,
+            version: Version(0),
+            next: AtomicPtr::new(null_mut()),
+        }))
+    }
+    /// Replace `old` with the unpublished `list`, unless `old` is no longer
+    /// current, in which case the current list is returned.
+    unsafe fn try_publish(
+        &self,
+        list: *mut List<T>,
+        old: *mut List<T>,
+    ) -> Result<Version, *mut List<T>> {
+        let version = (*old).version.next();
+        (*list).version = version;
+        // The old value is kept on our list until `clean` is called,
+        // since there may still be references to it.
+        (*list).next.store(old, Ordering::Relaxed);
+        self.inner
+            .compare_exchange(old, list, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| version)
+    }
     pub fn clean(&mut self) {
-        let inner = self.inner.load(Ordering::Acquire);
+        let inner = *self.inner.get_mut();
         let next = unsafe { (*inner).next.swap(null_mut(), Ordering::Acquire) };
-        let _free_this = unsafe { Box::from_raw(next) };
+        if !next.is_null() {
+            let _free_this = unsafe { Box::from_raw(next) };
+        }
+    }
+}
+impl<'a, T: RcuClone> BoxRcu<T> {
+    pub fn new(x: T) -> Self {
+        BoxRcu::from_box(Box::new(x))
+    }
+    /// Obtain a guard for modifying the value
+    ///
+    /// The value is only copied when the guard is first dereferenced
+    /// mutably, and a guard that is never dereferenced mutably publishes
+    /// nothing when it is dropped.
+    pub fn update(&'a self) -> Guard<'a, T> {
+        Guard {
+            value: None,
+            thebox: self,
+            same: None,
+            in_place: false,
+            modified_in_place: false,
+        }
+    }
+    /// Obtain a guard for modifying the value in place
+    ///
+    /// Since we own the value, a `&mut self` proves that no one can be
+    /// reading it, so there is no need to make a copy.  Old copies are
+    /// cleaned up first.
+    /// ```
+    /// let mut x = rcu_clean::BoxRcu::new(vec![0; 1000]);
+    /// x.update_mut()[0] = 1; // No copy of the vec is made.
+    /// assert_eq!(x[0], 1);
+    /// ```
+    pub fn update_mut(&'a mut self) -> Guard<'a, T> {
+        self.clean();
+        let mut guard = self.update();
+        guard.in_place = true;
+        guard
+    }
+    /// Obtain a guard that only publishes the value if it has changed
+    ///
+    /// When the guard is dropped, the modified copy is compared with the
+    /// current value, and if they are equal the copy is discarded, so no
+    /// extra copy is retained until the next `clean`.
+    /// ```
+    /// let x = rcu_clean::BoxRcu::new(vec![1, 2]);
+    /// x.update_if_changed().sort();
+    /// assert_eq!(*x, vec![1, 2]);
+    /// ```
+    pub fn update_if_changed(&'a self) -> Guard<'a, T>
+    where
+        T: PartialEq,
+    {
+        let mut guard = self.update();
+        guard.same = Some(T::eq);
+        guard
+    }
+    /// Modify the value only if it is still at the `expected` version
+    ///
+    /// This allows optimistic concurrency: read the value along with its
+    /// version, compute what you want to change, and then apply the change
+    /// only if no one else has published a new value in the meantime.
+    /// ```
+    /// let x = rcu_clean::BoxRcu::new(1);
+    /// let (_, version) = x.read_versioned();
+    /// assert!(x.update_if_version(version, |v| *v = 2).is_ok());
+    /// assert!(x.update_if_version(version, |v| *v = 3).is_err());
+    /// assert_eq!(*x, 2);
+    /// ```
+    pub fn update_if_version<R>(
+        &self,
+        expected: Version,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Result<R, Conflict> {
+        let old = self.inner.load(Ordering::Acquire);
+        let actual = unsafe { (*old).version };
+        if actual != expected {
+            return Err(Conflict { expected, actual });
+        }
+        let mut value = Box::new(unsafe { (*(*old).value).rcu_clone() });
+        let result = f(&mut value);
+        let list = Self::new_list(value);
+        match unsafe { self.try_publish(list, old) } {
+            Ok(_) => Ok(result),
+            Err(current) => unsafe {
+                // Someone else published first, so our list must not free
+                // the old values it points to.
+                (*list).next.store(null_mut(), Ordering::Relaxed);
+                drop(Box::from_raw(list));
+                Err(Conflict {
+                    expected,
+                    actual: (*current).version,
+                })
+            },
+        }
     }
 }
 
-pub struct Guard<'a, T: Clone> {
-    list: AtomicPtr<List<T>>,
+pub struct Guard<'a, T: RcuClone> {
+    value: Option<Box<T>>,
     thebox: &'a BoxRcu<T>,
+    same: Option<fn(&T, &T) -> bool>,
+    in_place: bool,
+    modified_in_place: bool,
 }
-impl<'a, T: Clone> std::ops::Deref for Guard<'a, T> {
+impl<'a, T: RcuClone> std::ops::Deref for Guard<'a, T> {
     type Target = T;
     fn deref(&self) -> &T {
-        unsafe { &(*self.list.load(Ordering::Acquire)).value }
+        if let Some(ref value) = self.value {
+            value
+        } else {
+            self.thebox
+        }
     }
 }
-impl<'a, T: Clone> std::ops::DerefMut for Guard<'a, T> {
+impl<'a, T: RcuClone> std::ops::DerefMut for Guard<'a, T> {
     fn deref_mut(&mut self) -> &mut T {
-        unsafe { &mut (*self.list.load(Ordering::Acquire)).value }
+        if self.in_place {
+            self.modified_in_place = true;
+            return unsafe { &mut (*self.thebox.inner.load(Ordering::Acquire)).value };
+        }
+        let thebox = self.thebox;
+        self.value
+            .get_or_insert_with(|| Box::new((**thebox).rcu_clone()))
     }
 }
-impl<'a, T: Clone> Drop for Guard<'a, T> {
+impl<'a, T: RcuClone> Drop for Guard<'a, T> {
     fn drop(&mut self) {
-        self.thebox
-            .inner
-            .store(self.list.load(Ordering::Acquire), Ordering::Release);
+        if let Some(value) = self.value.take() {
+            let unchanged = match self.same {
+                Some(same) => same(&value, self.thebox),
+                None => false,
+            };
+            if !unchanged {
+                self.thebox.publish(value);
+            }
+        }
+        if self.modified_in_place {
+            let list = self.thebox.inner.load(Ordering::Acquire);
+            unsafe { (*list).version = (*list).version.next() };
+        }
+    }
+}
+
+impl<'a, T: RcuClone> Guard<'a, T> {
+    /// Make a guard for a component of the value being updated
+    ///
+    /// This is an associated function that needs to be used as
+    /// `Guard::map(...)`, so that it does not interfere with methods of
+    /// `T`.  This has the same semantics as `std::cell::RefMut::map`.  The
+    /// entire updated value is published when the [MappedGuard] is dropped.
+    ///
+    /// ```
+    /// let x = rcu_clean::BoxRcu::new((1, 2));
+    /// {
+    ///     let update = x.update();
+    ///     let mut second = rcu_clean::boxrcu::Guard::map(update, |v| &mut v.1);
+    ///     *second = 7;
+    ///     assert_eq!(*x, (1, 2));
+    /// }
+    /// assert_eq!(*x, (1, 7));
+    /// ```
+    pub fn map<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(orig: Self, f: F) -> MappedGuard<'a, T, U> {
+        MappedGuard::new(orig, f)
+    }
+    /// Make a guard for an optional component of the value being updated
+    ///
+    /// This has the same semantics as `std::cell::RefMut::filter_map`.  If
+    /// the closure returns `None`, the original guard is handed back.
+    pub fn filter_map<U: ?Sized, F: FnOnce(&mut T) -> Option<&mut U>>(
+        orig: Self,
+        f: F,
+    ) -> Result<MappedGuard<'a, T, U>, Self> {
+        MappedGuard::filter_new(orig, f)
     }
 }
+
+/// A guard for a component of a value being updated
+///
+/// This is created by [Guard::map], and publishes the entire updated value
+/// when it is dropped.
+pub type MappedGuard<'a, T, U> = crate::MappedGuard<Guard<'a, T>, U>;
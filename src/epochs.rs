@@ -0,0 +1,51 @@
+//! Retired values, queued by epoch until no grace could be reading them
+
+use std::collections::BTreeMap;
+
+/// The most values we take from the queue at a time, so that freeing a long
+/// backlog neither recurses nor keeps everyone else waiting
+const RECLAIM_BATCH: usize = 64;
+
+/// Retired values, indexed by the epoch from which graces can no longer read
+/// them
+///
+/// Every source of grace counts epochs, bumping the count each time it
+/// retires values, and keeps its open graces by the epoch in which they
+/// began.  A value retired in some epoch may be read by any grace that began
+/// before it, so it expires once the oldest open grace is at least that new.
+pub(crate) struct EpochQueue<G> {
+    garbage: BTreeMap<u64, Vec<G>>,
+}
+
+impl<G> Default for EpochQueue<G> {
+    fn default() -> Self {
+        EpochQueue {
+            garbage: BTreeMap::new(),
+        }
+    }
+}
+
+impl<G> EpochQueue<G> {
+    /// Retire values that graces which began before `epoch` may still be
+    /// reading
+    pub(crate) fn retire(&mut self, epoch: u64, old: impl IntoIterator<Item = G>) {
+        self.garbage.entry(epoch).or_default().extend(old);
+    }
+    /// Take a batch of retired values that none of the open `readers` could
+    /// be reading
+    ///
+    /// Callers should free each batch without holding their lock, since
+    /// dropping the values could run any code at all, and keep taking
+    /// batches until one comes back empty.
+    pub(crate) fn expired<R>(&mut self, readers: &BTreeMap<u64, R>) -> Vec<G> {
+        let oldest = readers.keys().next().copied().unwrap_or(u64::MAX);
+        let mut expired = Vec::new();
+        while expired.len() < RECLAIM_BATCH {
+            match self.garbage.first_entry() {
+                Some(entry) if *entry.key() <= oldest => expired.extend(entry.remove()),
+                _ => break,
+            }
+        }
+        expired
+    }
+}
@@ -32,48 +32,293 @@
 //! pointer read, but should not be much so, and should be far cheaper than a
 //! `RwLock::read` which would be the `std` alternative for a data structure
 //! with many readers and few writers.
-use std::ops::Deref;
-use std::sync::atomic::{AtomicPtr, Ordering};
-use std::sync::{Arc, Mutex};
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
 use once_cell::sync::OnceCell;
 
+use crate::epochs::EpochQueue;
+use crate::history::History;
+use crate::recycle::Recycler;
+use crate::{Conflict, RcuClone, Version};
+
+pub mod local;
+pub mod scoped;
+pub use self::scoped::scope;
+
 /// A reference-counted RCU pointer with grace periods
-pub struct Rcu<T>(AtomicPtr<T>);
+///
+/// Cloning an `Rcu` gives a new, independent pointer that starts out with
+/// the same value.  For another pointer to the same place, so that an update
+/// made through one is visible through both, use [`Rcu::share`].
+pub struct Rcu<T: ?Sized> {
+    inner: Arc<Inner<T>>,
+}
+
+struct Inner<T: ?Sized> {
+    ptr: AtomicPtr<Node<T>>,
+    // The tasks waiting in an `RcuWatcher` for the next update
+    wakers: Mutex<Vec<Waker>>,
+    history: Mutex<Option<History<T>>>,
+    combiner: Mutex<Combiner<T>>,
+    recycler: Mutex<Recycler<Arc<T>, Reclaimer<T>>>,
+    // How to measure the size of a retired value
+    weigh: Mutex<fn(&T) -> usize>,
+    // We own an `Arc<T>`, so we are `Send` and `Sync` under the same
+    // conditions it is.
+    _marker: PhantomData<Arc<T>>,
+}
+
+/// What [`Rcu::with_reclaimer`] does with a retired value
+type Reclaimer<T> = fn(Arc<T>);
+
+/// A published version of the value
+///
+/// `T` may be unsized, so we need a (thin) pointer to the node in order to
+/// swap it atomically.
+struct Node<T: ?Sized> {
+    value: Arc<T>,
+    version: Version,
+    // Graces that began before this epoch must not see this value, but
+    // rather follow `prev` to the value it replaced.  This is how we hide
+    // transactions from graces that were open when they were committed.
+    visible_from: u64,
+    // The epoch in which this value was published, before which snapshots
+    // must not see it.
+    published: u64,
+    prev: *const Node<T>,
+}
+// The `prev` pointer is only followed while the node it points to is kept
+// alive by a grace period.
+unsafe impl<T: ?Sized + Send + Sync> Send for Node<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for Node<T> {}
+
+/// A node that has been replaced, which is dropped once the graces that
+/// could be reading it are over
+///
+/// Its value then goes back to the `Rcu` it came from to be recycled, if the
+/// `Rcu` still exists.
+struct Retired<T: ?Sized> {
+    node: Option<Box<Node<T>>>,
+    home: Weak<Inner<T>>,
+    // The size of the value, as measured by the `Rcu`'s weigher
+    bytes: usize,
+}
+impl<T: ?Sized> Drop for Retired<T> {
+    fn drop(&mut self) {
+        let node = self.node.take().unwrap();
+        if let Some(inner) = self.home.upgrade() {
+            inner.recycle(node.value);
+        }
+    }
+}
 
-unsafe impl<T: Sync> Sync for Rcu<T> {}
-unsafe impl<T: Send> Send for Rcu<T> {}
+/// Something that must be retained until the graces that could be reading it
+/// are over
+trait Expiring: Send + Sync {
+    /// How many bytes we are retaining, for [`StallWarning`] and the budget
+    fn bytes(&self) -> usize;
+}
+impl<T: ?Sized + Send + Sync> Expiring for Retired<T> {
+    fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
 
-impl<T> Clone for Rcu<T> {
+impl<T: ?Sized> Clone for Rcu<T> {
+    /// A new pointer to our current value, which later updates to either
+    /// pointer do not affect
+    ///
+    /// The value itself is shared rather than copied, but none of our
+    /// history, spares, reclaimer or watchers come along.
     fn clone(&self) -> Self {
-        let p = self.0.swap(std::ptr::null_mut(), Ordering::Acquire);
-        let arc: Arc<T> = unsafe { Arc::from_raw(p) };
-        let other = arc.clone();
-        let _copy_i_am_keeping = Arc::into_raw(arc);
-        // Use the other copy for the clone
-        Rcu(AtomicPtr::new(Arc::into_raw(other) as *mut T))
+        let grace = Grace::new();
+        let current = self.read(&grace);
+        let rcu = Rcu {
+            inner: Arc::new(Inner::new(current.to_arc(), current.version())),
+        };
+        *rcu.inner.weigh.lock().unwrap() = *self.inner.weigh.lock().unwrap();
+        rcu
     }
 }
 
-impl<T> Drop for Rcu<T> {
+impl<T: ?Sized> Drop for Inner<T> {
     fn drop(&mut self) {
-        let p = self.0.swap(std::ptr::null_mut(), Ordering::Acquire);
-        let _to_free = unsafe { Arc::from_raw(p) };
+        let _to_free = unsafe { Box::from_raw(*self.ptr.get_mut()) };
+        // Let any watchers know that there will be no more updates.
+        for waker in self.wakers.get_mut().unwrap().drain(..) {
+            waker.wake();
+        }
     }
 }
 
-impl<T> From<Arc<T>> for Rcu<T> {
+impl<T: ?Sized> From<Arc<T>> for Rcu<T> {
     fn from(b: Arc<T>) -> Self {
-        Rcu(std::sync::atomic::AtomicPtr::new(Arc::into_raw(b) as *mut T))
+        Rcu {
+            inner: Arc::new(Inner::new(b, Version(0))),
+        }
     }
 }
 
-impl<T: Clone + Send + Sync + 'static> Rcu<T> {
-    /// Allocate a new Rcu pointer
+impl<T: ?Sized> Inner<T> {
+    fn new(value: Arc<T>, version: Version) -> Self {
+        Inner {
+            ptr: AtomicPtr::new(Box::into_raw(Box::new(Node {
+                value,
+                version,
+                visible_from: 0,
+                published: 0,
+                prev: std::ptr::null(),
+            }))),
+            wakers: Mutex::new(Vec::new()),
+            history: Mutex::new(None),
+            combiner: Mutex::new(Combiner {
+                queue: Vec::new(),
+                combining: false,
+            }),
+            recycler: Mutex::new(Recycler::new()),
+            weigh: Mutex::new(std::mem::size_of_val),
+            _marker: PhantomData,
+        }
+    }
+    /// Keep a value that no grace can be reading for reuse, or reclaim it
+    fn recycle(&self, mut value: Arc<T>) {
+        if Arc::get_mut(&mut value).is_none() {
+            // It is still in our history, or someone has called `to_arc`.
+            return;
+        }
+        let mut recycler = self.recycler.lock().unwrap();
+        if let Some(value) = recycler.keep(value) {
+            let reclaimer = recycler.reclaimer();
+            drop(recycler);
+            if let Some(reclaim) = reclaimer {
+                reclaim(value);
+            }
+        }
+    }
+}
+
+impl<T: ?Sized> Rcu<T> {
+    /// Allocate a new Rcu pointer holding an already boxed value
     ///
-    /// This is no more expensive than `Arc::new`.
-    pub fn new(value: T) -> Self {
-        Self::from(Arc::new(value))
+    /// This allows `T` to be unsized, such as a `str`, a slice or a trait
+    /// object.
+    /// ```
+    /// use rcu_clean::graceful::{Grace, Rcu};
+    /// trait Handler: Send + Sync {
+    ///     fn handle(&self) -> usize;
+    /// }
+    /// struct Constant(usize);
+    /// impl Handler for Constant {
+    ///     fn handle(&self) -> usize { self.0 }
+    /// }
+    /// let h: Rcu<dyn Handler> = Rcu::from_box(Box::new(Constant(1)));
+    /// h.update_from(|old| Box::new(Constant(old.handle() + 1)));
+    /// assert_eq!(2, h.read(&Grace::new()).handle());
+    /// ```
+    pub fn from_box(value: Box<T>) -> Self {
+        Self::from(Arc::from(value))
+    }
+    /// Another pointer to the same place as this one
+    ///
+    /// Unlike [`clone`](Clone::clone), which gives an independent pointer,
+    /// an update made through either pointer is visible through both, just
+    /// as for clones of an `ArcRcu`.
+    /// ```
+    /// use rcu_clean::graceful::Rcu;
+    /// let v = Rcu::new(1);
+    /// let shared = v.share();
+    /// let forked = v.clone();
+    /// v.update(|v| *v = 2);
+    /// assert_eq!(2, *shared.load_full());
+    /// assert_eq!(1, *forked.load_full());
+    /// ```
+    pub fn share(&self) -> Self {
+        Rcu {
+            inner: self.inner.clone(),
+        }
+    }
+    /// Obtain an owned reference to the current value
+    ///
+    /// Unlike [`Rcu::read`], the returned `Arc` is not tied to any grace
+    /// period, so it may be kept around for as long as you like or sent to
+    /// another thread.  It keeps exactly the version that was current when
+    /// `load_full` was called alive, and later updates to the `Rcu` will not
+    /// be visible through it.
+    ///
+    /// This is more expensive than a `read`, since it needs to create a
+    /// `Grace` and bump a reference count.  If you already have a `Grace`
+    /// handy, [`RcuGuard::to_arc`] will save you creating another.
+    /// ```
+    /// let v = rcu_clean::graceful::Rcu::new(vec![1,2,3]);
+    /// let snapshot = v.load_full();
+    /// v.update(|v| v.push(4));
+    /// assert_eq!(3, snapshot.len());
+    /// assert_eq!(4, v.load_full().len());
+    /// ```
+    pub fn load_full(&self) -> Arc<T> {
+        self.read(&Grace::new()).to_arc()
+    }
+    /// Create a [`WeakRcu`] pointer to this `Rcu`
+    ///
+    /// This is an associated function that needs to be used as
+    /// `Rcu::downgrade(...)`, just like `Arc::downgrade`.
+    pub fn downgrade(this: &Self) -> WeakRcu<T> {
+        WeakRcu {
+            inner: Arc::downgrade(&this.inner),
+        }
+    }
+    /// Watch for updates to this `Rcu`
+    ///
+    /// The [`RcuWatcher`] provides futures that resolve when a new value is
+    /// published, using only `std::task`, so it works with any async
+    /// runtime.  Only updates published after `subscribe` is called are
+    /// reported.
+    /// ```
+    /// # fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    /// #     struct Unpark(std::thread::Thread);
+    /// #     impl std::task::Wake for Unpark {
+    /// #         fn wake(self: std::sync::Arc<Self>) { self.0.unpark() }
+    /// #     }
+    /// #     let waker = std::sync::Arc::new(Unpark(std::thread::current())).into();
+    /// #     let mut cx = std::task::Context::from_waker(&waker);
+    /// #     let mut f = Box::pin(f);
+    /// #     loop {
+    /// #         match f.as_mut().poll(&mut cx) {
+    /// #             std::task::Poll::Ready(x) => return x,
+    /// #             std::task::Poll::Pending => std::thread::park(),
+    /// #         }
+    /// #     }
+    /// # }
+    /// let v = rcu_clean::graceful::Rcu::new(1);
+    /// let mut watcher = v.subscribe();
+    /// let w = v.share();
+    /// std::thread::spawn(move || w.update(|v| *v = 2));
+    /// let new = block_on(watcher.changed()).unwrap();
+    /// assert_eq!(2, *new);
+    /// ```
+    pub fn subscribe(&self) -> RcuWatcher<T> {
+        RcuWatcher {
+            seen: self.version(),
+            rcu: Rcu::downgrade(self),
+        }
+    }
+    /// The version of the current value
+    ///
+    /// Every update publishes a new, higher version.  Use
+    /// [`RcuGuard::version`] to find the version of a value you have read.
+    pub fn version(&self) -> Version {
+        self.read(&Grace::new()).version()
     }
     /// Read the pointer, with the given grace period
     ///
@@ -81,11 +326,326 @@ impl<T: Clone + Send + Sync + 'static> Rcu<T> {
     /// thus quite cheap.  It returns an [`RcuGuard`] which cannot outlive the
     /// grace period.  The guard implements `Deref` that is a noop, so overall
     /// the cost of reading from an `Rcu` is just the cost of a single atomic
-    /// pointer load (and then of course dereferencing that pointer).
-    pub fn read<'a, 'b: 'a>(&'b self, _grace: &'a Grace) -> RcuGuard<'a, T> {
-        let p = self.0.load(Ordering::Acquire);
+    /// pointer load (and then of course following that pointer to the value).
+    ///
+    /// Values published by a [`transaction`] are not visible to a `Grace`
+    /// that was already open when it was committed, and no values published
+    /// after a [`Grace::snapshot`] was taken are visible to it.  Reading
+    /// through such an old `Grace` needs to look back to older values, one
+    /// at a time, so it costs time in proportion to the number of updates
+    /// that it cannot see.
+    pub fn read<'a, 'b: 'a>(&'b self, grace: &'a Grace) -> RcuGuard<'a, T> {
+        let mut node = unsafe { &*self.inner.ptr.load(Ordering::Acquire) };
+        while node.visible_from > grace.epoch || (grace.snapshot && node.published > grace.epoch) {
+            // The older node was replaced after `grace` began, so it is still
+            // retained.
+            node = unsafe { &*node.prev };
+        }
+        RcuGuard {
+            node,
+            ptr: &node.value,
+        }
+    }
+    /// Read the current value, even if `grace` would see an older one
+    ///
+    /// The current value is retained as long as `grace` is, so this is only
+    /// wrong when `grace` should not see it, which is for the caller to
+    /// rule out.
+    fn read_current<'a>(&'a self, _grace: &'a Grace) -> RcuGuard<'a, T> {
+        let node = unsafe { &*self.inner.ptr.load(Ordering::Acquire) };
         RcuGuard {
-            ptr: unsafe { &*p },
+            node,
+            ptr: &node.value,
+        }
+    }
+}
+
+impl<T: ?Sized + Send + Sync + 'static> Rcu<T> {
+    /// Publish a new value computed from the current one
+    ///
+    /// Since your function creates an entirely new boxed value, this works
+    /// even when `T` is unsized and cannot be cloned.  As with
+    /// [`Rcu::update`], the old value is retained until the last `Grace`
+    /// that is open when we publish is dropped.
+    pub fn update_from(&self, f: impl FnOnce(&T) -> Box<T>) {
+        check_budget();
+        let new = f(&self.read(&Grace::new()));
+        self.publish(Arc::from(new), None).ok();
+    }
+    /// Measure the values we retire with `weigh`, for [`on_stall`] and
+    /// [`set_budget`]
+    ///
+    /// By default a value's size is just `std::mem::size_of_val`, which does
+    /// not count anything that it owns on the heap.
+    /// ```
+    /// let v = rcu_clean::graceful::Rcu::new(vec![0u8; 1000]).with_weigher(|v| v.capacity());
+    /// ```
+    pub fn with_weigher(self, weigh: fn(&T) -> usize) -> Self {
+        *self.inner.weigh.lock().unwrap() = weigh;
+        self
+    }
+    /// Compute a value from this one, recomputing only when it changes
+    ///
+    /// The [`Derived`] value is computed at most once for each version of
+    /// this `Rcu`, no matter how many readers there are, and only when it is
+    /// read.  Old derived values are retained until the grace periods that
+    /// could be reading them are over, just like the values of an `Rcu`.
+    /// ```
+    /// use rcu_clean::graceful::{Grace, Rcu};
+    /// let words = Rcu::new(vec!["hello", "world"]);
+    /// let total = words.derive(|w| w.iter().map(|w| w.len()).sum::<usize>());
+    /// let grace = Grace::new();
+    /// assert_eq!(10, *total.read(&grace));
+    /// words.update(|w| w.push("!"));
+    /// assert_eq!(11, *total.read(&grace));
+    /// ```
+    pub fn derive<U: Send + Sync + 'static>(
+        &self,
+        f: impl Fn(&T) -> U + Send + Sync + 'static,
+    ) -> Derived<U> {
+        Derived::new(self.share(), f)
+    }
+    /// Keep the last `len` published values, so that we can revert to them
+    ///
+    /// Since values are already reference counted, keeping history costs no
+    /// copies, but it does keep old values alive.  A `len` of zero stops
+    /// keeping history.  The history is shared by every pointer from
+    /// [`Rcu::share`], but not by clones.
+    /// ```
+    /// let config = rcu_clean::graceful::Rcu::new("good");
+    /// config.keep_history(10);
+    /// config.update(|c| *c = "bad");
+    /// config.revert();
+    /// assert_eq!("good", *config.load_full());
+    /// let versions: Vec<_> = config.history().map(|(v, _)| v).collect();
+    /// assert_eq!(3, versions.len());
+    /// assert_eq!(config.version(), versions[0]);
+    /// ```
+    pub fn keep_history(&self, len: usize) {
+        let mut history = self.inner.history.lock().unwrap();
+        if len == 0 {
+            *history = None;
+        } else if let Some(history) = history.as_mut() {
+            history.set_limit(len);
+        } else {
+            let mut new = History::new(len);
+            let grace = Grace::new();
+            let current = self.read(&grace);
+            new.record(current.version(), current.to_arc());
+            *history = Some(new);
+        }
+    }
+    /// The values in our history, newest first, along with their versions
+    ///
+    /// This is empty unless [`Rcu::keep_history`] has been called.
+    pub fn history(&self) -> impl Iterator<Item = (Version, Arc<T>)> {
+        let history = self.inner.history.lock().unwrap();
+        let entries = history.as_ref().map(|h| h.entries());
+        entries.unwrap_or_default().into_iter()
+    }
+    /// Undo the latest update, by republishing the value before it
+    ///
+    /// The old value is published as a new version, which is returned.  If
+    /// there is no earlier value in the history, nothing is published.
+    /// Reverting again undoes the update before that, and so on.
+    /// ```
+    /// let config = rcu_clean::graceful::Rcu::new("first");
+    /// config.keep_history(10);
+    /// config.update(|c| *c = "good");
+    /// config.update(|c| *c = "bad");
+    /// config.revert();
+    /// assert_eq!("good", *config.load_full());
+    /// config.revert();
+    /// assert_eq!("first", *config.load_full());
+    /// assert_eq!(None, config.revert());
+    /// ```
+    pub fn revert(&self) -> Option<Version> {
+        check_budget();
+        let (restored, previous) = self.inner.history.lock().unwrap().as_ref()?.previous()?;
+        let version = self.publish(previous, None).ok()?;
+        self.restored(version, restored);
+        Some(version)
+    }
+    /// Republish the value with the given version from our history
+    ///
+    /// The old value is published as a new version, which is returned.  If
+    /// `version` is not in the history, nothing is published.
+    pub fn revert_to(&self, version: Version) -> Option<Version> {
+        check_budget();
+        let value = self.inner.history.lock().unwrap().as_ref()?.get(version)?;
+        let republished = self.publish(value, None).ok()?;
+        self.restored(republished, version);
+        Some(republished)
+    }
+    fn restored(&self, version: Version, restored: Version) {
+        if let Some(history) = self.inner.history.lock().unwrap().as_mut() {
+            history.restored(version, restored);
+        }
+    }
+    /// Publish a new value, unless we expect a version that is not current
+    ///
+    /// If there is a budget for retained values, this waits for the budget
+    /// first, as set by [`set_budget`].  Callers check for
+    /// [`Backpressure::Fail`] when the update begins.
+    fn publish(&self, new: Arc<T>, expected: Option<Version>) -> Result<Version, Conflict> {
+        let period = within_budget(source_of_grace().0.lock().unwrap());
+        self.publish_with(period, new, |actual| match expected {
+            Some(expected) if expected != actual => Err(Conflict { expected, actual }),
+            _ => Ok(actual.next()),
+        })
+    }
+    /// Publish a new value, with the version chosen based on the current one
+    ///
+    /// We need to hold the grace-period lock.  Since we have just one source
+    /// of grace, this means no other critical update sections are ongoing,
+    /// and all updates are totally ordered.  It also means that no one can
+    /// start a new grace period while we're working on this change.
+    fn publish_with(
+        &self,
+        mut period: MutexGuard<'_, Period>,
+        new: Arc<T>,
+        version: impl FnOnce(Version) -> Result<Version, Conflict>,
+    ) -> Result<Version, Conflict> {
+        let version = version(self.current_version())?;
+        period.epoch += 1;
+        let old = self.install(new, version, period.epoch, false);
+        period.retire(vec![old]);
+        reclaim(period);
+
+        self.wake_watchers();
+        Ok(version)
+    }
+    /// The version of the current value, which must be read while holding
+    /// the grace-period lock
+    fn current_version(&self) -> Version {
+        // Since no one else can publish while we hold the lock, the current
+        // node cannot be freed out from under us.
+        unsafe { (*self.inner.ptr.load(Ordering::Acquire)).version }
+    }
+    /// Make `new` the current value, returning the old node so that it may
+    /// be retired
+    ///
+    /// This must be called while holding the grace-period lock, with the
+    /// `epoch` in which we are publishing.  An `atomic` value is only visible
+    /// to graces that began in that epoch or later, while other values are
+    /// visible to whichever graces could see the value they replace.
+    fn install(
+        &self,
+        new: Arc<T>,
+        version: Version,
+        epoch: u64,
+        atomic: bool,
+    ) -> Box<dyn Expiring> {
+        let prev = self.inner.ptr.load(Ordering::Acquire);
+        if let Some(history) = self.inner.history.lock().unwrap().as_mut() {
+            history.record(version, new.clone());
+        }
+        let new = Box::into_raw(Box::new(Node {
+            value: new,
+            version,
+            visible_from: if atomic {
+                epoch
+            } else {
+                unsafe { (*prev).visible_from }
+            },
+            published: epoch,
+            prev,
+        }));
+        let old = unsafe { Box::from_raw(self.inner.ptr.swap(new, Ordering::Release)) };
+        let weigh = *self.inner.weigh.lock().unwrap();
+        Box::new(Retired {
+            bytes: weigh(&old.value),
+            node: Some(old),
+            home: Arc::downgrade(&self.inner),
+        })
+    }
+    fn wake_watchers(&self) {
+        for waker in self.inner.wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl<T: RcuClone + Send + Sync + 'static> Rcu<T> {
+    /// Allocate a new Rcu pointer
+    ///
+    /// This is no more expensive than `Arc::new`.
+    pub fn new(value: T) -> Self {
+        Self::from(Arc::new(value))
+    }
+    /// Allocate a new Rcu pointer to a value that refers to itself
+    ///
+    /// Like `Arc::new_cyclic`, this gives your closure a [`WeakRcu`] that
+    /// will point to the `Rcu` being created, but cannot be upgraded until
+    /// the closure has returned.
+    /// ```
+    /// use rcu_clean::graceful::{Rcu, WeakRcu};
+    /// #[derive(Clone)]
+    /// struct Node {
+    ///     me: WeakRcu<Node>,
+    ///     value: usize,
+    /// }
+    /// let node = Rcu::new_cyclic(|me| Node { me: me.clone(), value: 1 });
+    /// node.update(|n| n.value = 2);
+    /// let me = node.load_full().me.upgrade().unwrap();
+    /// assert_eq!(2, me.load_full().value);
+    /// ```
+    pub fn new_cyclic(f: impl FnOnce(&WeakRcu<T>) -> T) -> Self {
+        Rcu {
+            inner: Arc::new_cyclic(|weak| {
+                let value = f(&WeakRcu {
+                    inner: weak.clone(),
+                });
+                Inner::new(Arc::new(value), Version(0))
+            }),
+        }
+    }
+    /// Keep up to `spares` retired values for reuse by later updates
+    ///
+    /// Normally each value is dropped once the graces that could be reading
+    /// it are over.  With spares, we instead keep a few of those values, so
+    /// that later updates can reuse their allocations rather than making new
+    /// ones.  The spares are not dropped until they are reused or we are.
+    pub fn with_spares(self, spares: usize) -> Self {
+        let extra = self.inner.recycler.lock().unwrap().set_spares(spares);
+        drop(extra);
+        self
+    }
+    /// Hand each value we retire to `reclaimer`, rather than dropping it
+    ///
+    /// A reclaimer gets every retired value, even if we were asked to keep
+    /// spares, so it can recycle them however it likes, or send them to
+    /// another thread to be dropped.  It is called on whichever thread ends
+    /// the last grace that could read the value.  The `Arc` it is given is
+    /// the only one left, so [`Arc::try_unwrap`] will succeed.  Values that
+    /// are still in our history or held by someone who called
+    /// [`RcuGuard::to_arc`] are not retired, but rather dropped by whoever
+    /// lets go of them last.
+    /// ```
+    /// use rcu_clean::graceful::Rcu;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    /// static RECLAIMED: AtomicUsize = AtomicUsize::new(0);
+    /// let v = Rcu::new(1).with_reclaimer(|v: Arc<usize>| {
+    ///     RECLAIMED.fetch_add(*v, Ordering::Relaxed);
+    /// });
+    /// v.update(|v| *v = 2);
+    /// v.update(|v| *v = 3);
+    /// assert_eq!(1 + 2, RECLAIMED.load(Ordering::Relaxed));
+    /// ```
+    pub fn with_reclaimer(self, reclaimer: fn(Arc<T>)) -> Self {
+        self.inner.recycler.lock().unwrap().set_reclaimer(reclaimer);
+        self
+    }
+    /// A private copy of `value`, reusing a retired allocation if we can
+    fn copy(&self, value: &T) -> Arc<T> {
+        let spare = self.inner.recycler.lock().unwrap().take();
+        match spare {
+            Some(mut new) => {
+                private(&mut new).rcu_clone_from(value);
+                new
+            }
+            None => Arc::new(value.rcu_clone()),
         }
     }
     /// Modify the contents of the `Rcu`.
@@ -100,34 +660,765 @@ impl<T: Clone + Send + Sync + 'static> Rcu<T> {
     /// Note that simultaneous updates to the same pointer are possible and are
     /// *safe* but are not *recommended*.
     pub fn update(&self, f: impl FnOnce(&mut T)) {
-        // start by getting the grace period for our copy.
-        let mut new = Arc::new(self.read(&Grace::new()).clone());
-        f(Arc::get_mut(&mut new).unwrap());
+        let mut guard = self.write();
+        f(&mut guard);
+    }
+    /// Modify the contents of the `Rcu`, unless graces retain too much
+    ///
+    /// This is like [`Rcu::update`], except that when the old values that
+    /// graces are retaining exceed the budget set by [`set_budget`], this
+    /// publishes nothing and returns an error, rather than blocking or
+    /// panicking.
+    /// ```
+    /// use rcu_clean::graceful::{set_budget, Backpressure, Grace, Rcu};
+    /// set_budget(Some(100), Backpressure::Block);
+    /// let v = Rcu::new([0u8; 60]);
+    /// let grace = Grace::new();
+    /// assert!(v.try_update(|v| v[0] = 1).is_ok());
+    /// assert!(v.try_update(|v| v[0] = 2).is_ok());
+    /// assert!(v.try_update(|v| v[0] = 3).is_err()); // retaining 120 bytes
+    /// drop(grace);
+    /// assert!(v.try_update(|v| v[0] = 3).is_ok());
+    /// ```
+    pub fn try_update(&self, f: impl FnOnce(&mut T)) -> Result<(), OverBudget> {
+        if let Some(over) = source_of_grace().0.lock().unwrap().over_budget() {
+            return Err(over);
+        }
+        let mut new = self.copy(&self.read(&Grace::new()));
+        f(private(&mut new));
+        let period = source_of_grace().0.lock().unwrap();
+        if let Some(over) = period.over_budget() {
+            return Err(over);
+        }
+        self.publish_with(period, new, |actual| Ok(actual.next()))
+            .ok();
+        Ok(())
+    }
+    /// Modify the contents of the `Rcu`, in place if possible
+    ///
+    /// If no one else holds a reference to the current value (via a clone,
+    /// [`Rcu::share`] or [`WeakRcu`] of this `Rcu`, or an `Arc` from
+    /// [`Rcu::load_full`]), then
+    /// there is no need to copy the value, and much like `Arc::make_mut` we
+    /// modify it in place.  Otherwise this behaves just like [`Rcu::update`].
+    /// The `&mut self` proves that no `RcuGuard` is reading from this `Rcu`,
+    /// even if some `Grace` predates the last update.  We do copy while any
+    /// [`Grace::snapshot`] is open, since a snapshot must not see the value
+    /// change, and a snapshot that is taken while we modify in place waits
+    /// until we are done, so `f` must not take one.
+    /// ```
+    /// let mut v = rcu_clean::graceful::Rcu::new(vec![0; 1000]);
+    /// v.update_mut(|v| v[0] = 1); // No copy of the vec is made.
+    /// let snapshot = v.load_full();
+    /// v.update_mut(|v| v[0] = 2); // Here we copy, since `snapshot` exists.
+    /// assert_eq!(1, snapshot[0]);
+    /// assert_eq!(2, v.load_full()[0]);
+    /// ```
+    pub fn update_mut(&mut self, f: impl FnOnce(&mut T)) {
+        if let Some(inner) = Arc::get_mut(&mut self.inner) {
+            let node = unsafe { &mut **inner.ptr.get_mut() };
+            if let Some(value) = Arc::get_mut(&mut node.value) {
+                let mut period = source_of_grace().0.lock().unwrap();
+                if SNAPSHOTS.load(Ordering::SeqCst) == 0 {
+                    period.in_place += 1;
+                    drop(period);
+                    let _in_place = InPlace;
+                    f(value);
+                    node.version = node.version.next();
+                    return;
+                }
+            }
+        }
+        self.update(f);
+    }
+    /// Submit an update to be applied together with other concurrent updates
+    ///
+    /// When many threads update an `Rcu` at once, each [`Rcu::update`]
+    /// copies the value and publishes it separately.  With `enqueue_update`,
+    /// one thread becomes the combiner, and applies all of the updates that
+    /// have been submitted to a single copy, which it then publishes.  If no
+    /// other thread is combining, we combine, so the update has been
+    /// published by the time this returns.  Otherwise this returns right
+    /// away, and the returned [`EnqueuedUpdate`] lets you wait for the
+    /// version that contains your change.
+    ///
+    /// If an update panics, the panic propagates in the combining thread, and
+    /// every update that has not yet been published is abandoned.  With
+    /// [`Backpressure::Fail`], going over the budget panics here, in the
+    /// thread that enqueues, never in the combining thread.
+    /// ```
+    /// let counter = rcu_clean::graceful::Rcu::new(0);
+    /// let handles: Vec<_> = (0..4)
+    ///     .map(|_| {
+    ///         let counter = counter.share();
+    ///         std::thread::spawn(move || counter.enqueue_update(|c| *c += 1).wait())
+    ///     })
+    ///     .collect();
+    /// for h in handles {
+    ///     assert!(h.join().unwrap().is_some());
+    /// }
+    /// assert_eq!(4, *counter.load_full());
+    /// ```
+    pub fn enqueue_update(&self, f: impl FnOnce(&mut T) + Send + 'static) -> EnqueuedUpdate {
+        check_budget();
+        let ticket = Arc::new(Ticket::default());
+        let mut combiner = self.inner.combiner.lock().unwrap();
+        combiner.queue.push((Box::new(f), ticket.clone()));
+        if !combiner.combining {
+            combiner.combining = true;
+            drop(combiner);
+            self.combine();
+        }
+        EnqueuedUpdate { ticket }
+    }
+    /// Apply and publish enqueued updates until there are none left
+    fn combine(&self) {
+        loop {
+            let mut combiner = self.inner.combiner.lock().unwrap();
+            if combiner.queue.is_empty() {
+                combiner.combining = false;
+                return;
+            }
+            let (updates, tickets): (Vec<_>, Vec<_>) = combiner.queue.drain(..).unzip();
+            drop(combiner);
+            let mut batch = Batch {
+                combiner: &self.inner.combiner,
+                tickets,
+            };
+            let mut new = self.copy(&self.read(&Grace::new()));
+            for update in updates {
+                update(private(&mut new));
+            }
+            let version = self.publish(new, None).ok();
+            for ticket in batch.tickets.drain(..) {
+                ticket.resolve(version);
+            }
+        }
+    }
+    /// Obtain a guard for modifying the contents of the `Rcu`.
+    ///
+    /// The guard holds a private copy of the value, which you may mutate at
+    /// will.  When the guard is dropped, the `Rcu` is atomically updated to
+    /// point to the new value, just as with [`Rcu::update`].
+    ///
+    /// If you decide not to make the change after all, call
+    /// [`RcuWriteGuard::abort`].  A guard that is dropped while panicking is
+    /// aborted in the same way, so a panic partway through a modification can
+    /// never publish a half-modified value, and the `Rcu` is never poisoned.
+    /// ```
+    /// let v = rcu_clean::graceful::Rcu::new(vec![1,2,3]);
+    /// {
+    ///     let mut w = v.write();
+    ///     w.push(4);
+    ///     assert_eq!(3, v.load_full().len()); // not yet published
+    /// }
+    /// assert_eq!(4, v.load_full().len());
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// With [`Backpressure::Fail`], this panics if graces are retaining more
+    /// than the budget, rather than when the guard is dropped.
+    pub fn write(&self) -> RcuWriteGuard<'_, T> {
+        check_budget();
+        RcuWriteGuard {
+            new: Some(self.copy(&self.read(&Grace::new()))),
+            rcu: self,
+        }
+    }
+    /// Modify the contents only if they are still at the `expected` version
+    ///
+    /// This allows optimistic concurrency: read the value along with its
+    /// version, compute what you want to change, and then apply the change
+    /// only if no one else has published a new value in the meantime.  Your
+    /// closure is run on a private copy, which is discarded on a conflict.
+    /// ```
+    /// use rcu_clean::graceful::{Grace, Rcu};
+    /// let v = Rcu::new(1);
+    /// let version = v.read(&Grace::new()).version();
+    /// assert!(v.update_if_version(version, |v| *v = 2).is_ok());
+    /// assert!(v.update_if_version(version, |v| *v = 3).is_err());
+    /// assert_eq!(2, *v.load_full());
+    /// ```
+    pub fn update_if_version<R>(
+        &self,
+        expected: Version,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Result<R, Conflict> {
+        check_budget();
+        let mut new = {
+            let grace = Grace::new();
+            let current = self.read(&grace);
+            let actual = current.version();
+            if actual != expected {
+                return Err(Conflict { expected, actual });
+            }
+            self.copy(&current)
+        };
+        let result = f(private(&mut new));
+        self.publish(new, Some(expected))?;
+        Ok(result)
+    }
+}
+
+/// Our private copy of a value, which no one else can have a reference to
+fn private<T: ?Sized>(copy: &mut Arc<T>) -> &mut T {
+    Arc::get_mut(copy).expect("private copies are never shared")
+}
+
+type Update<T> = Box<dyn FnOnce(&mut T) + Send>;
+
+/// The updates waiting to be combined
+struct Combiner<T: ?Sized> {
+    queue: Vec<(Update<T>, Arc<Ticket>)>,
+    // Whether some thread is applying the updates in the queue
+    combining: bool,
+}
+
+/// The tickets for updates being combined, which we abandon if one of the
+/// updates panics
+struct Batch<'a, T: ?Sized> {
+    combiner: &'a Mutex<Combiner<T>>,
+    tickets: Vec<Arc<Ticket>>,
+}
+impl<'a, T: ?Sized> Drop for Batch<'a, T> {
+    fn drop(&mut self) {
+        if self.tickets.is_empty() {
+            return;
+        }
+        for ticket in self.tickets.drain(..) {
+            ticket.resolve(None);
+        }
+        // Since we are no longer combining, we abandon the updates that
+        // are waiting, rather than leave them waiting for someone else to
+        // combine.
+        let mut combiner = self.combiner.lock().unwrap_or_else(|e| e.into_inner());
+        for (_, ticket) in combiner.queue.drain(..) {
+            ticket.resolve(None);
+        }
+        combiner.combining = false;
+    }
+}
+
+#[derive(Default)]
+struct Ticket {
+    // `None` until the update is done, and then the version, if any
+    version: Mutex<Option<Option<Version>>>,
+    done: Condvar,
+}
+impl Ticket {
+    fn resolve(&self, version: Option<Version>) {
+        *self.version.lock().unwrap() = Some(version);
+        self.done.notify_all();
+    }
+}
+
+/// An update submitted with [`Rcu::enqueue_update`]
+pub struct EnqueuedUpdate {
+    ticket: Arc<Ticket>,
+}
+impl EnqueuedUpdate {
+    /// Block until the update has been published
+    ///
+    /// This returns the first version that contains the update, or `None`
+    /// if the update was abandoned because some update panicked.
+    pub fn wait(self) -> Option<Version> {
+        let mut version = self.ticket.version.lock().unwrap();
+        loop {
+            if let Some(version) = *version {
+                return version;
+            }
+            version = self.ticket.done.wait(version).unwrap();
+        }
+    }
+    /// Whether the update has been published (or abandoned)
+    pub fn is_finished(&self) -> bool {
+        self.ticket.version.lock().unwrap().is_some()
+    }
+}
+
+/// A weak pointer to an [`Rcu`]
+///
+/// This is created by [`Rcu::downgrade`], and like `std::sync::Weak` it does
+/// not keep the `Rcu` alive.  Upgrading gives an `Rcu` that sees the latest
+/// value published through any pointer from [`Rcu::share`].
+/// ```
+/// use rcu_clean::graceful::Rcu;
+/// let v = Rcu::new(1);
+/// let weak = Rcu::downgrade(&v);
+/// v.update(|v| *v = 2);
+/// assert_eq!(2, *weak.upgrade().unwrap().load_full());
+/// drop(v);
+/// assert!(weak.upgrade().is_none());
+/// ```
+pub struct WeakRcu<T: ?Sized> {
+    inner: Weak<Inner<T>>,
+}
+
+impl<T: ?Sized> WeakRcu<T> {
+    /// Create a `WeakRcu` that points to nothing
+    pub fn new() -> Self {
+        WeakRcu { inner: Weak::new() }
+    }
+    /// Attempt to get an [`Rcu`] pointer, if the `Rcu` still exists
+    pub fn upgrade(&self) -> Option<Rcu<T>> {
+        self.inner.upgrade().map(|inner| Rcu { inner })
+    }
+}
+
+impl<T: ?Sized> Clone for WeakRcu<T> {
+    fn clone(&self) -> Self {
+        WeakRcu {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: ?Sized> Default for WeakRcu<T> {
+    fn default() -> Self {
+        WeakRcu::new()
+    }
+}
+
+/// A receiver of updates to an [`Rcu`]
+///
+/// This is created by [`Rcu::subscribe`].  It hands out owned `Arc<T>`
+/// snapshots rather than `RcuGuard`s, so that no `Grace` needs to be held
+/// across an `.await`.  A watcher does not keep the `Rcu` alive, and once
+/// every pointer from [`Rcu::share`] has been dropped its futures resolve
+/// to `None`.
+///
+/// If several updates are published before the watcher is polled, it only
+/// reports the latest.
+pub struct RcuWatcher<T: ?Sized> {
+    rcu: WeakRcu<T>,
+    seen: Version,
+}
+
+impl<T: ?Sized> RcuWatcher<T> {
+    /// The version we have most recently reported
+    pub fn version(&self) -> Version {
+        self.seen
+    }
+    /// Wait for a value newer than the last one we reported
+    pub fn changed(&mut self) -> Changed<'_, T> {
+        Changed { watcher: self }
+    }
+    /// Wait for a value newer than the last one we reported, with its version
+    pub fn next_version(&mut self) -> NextVersion<'_, T> {
+        NextVersion { watcher: self }
+    }
+    /// Poll for a value newer than the last one we reported
+    ///
+    /// This has the same signature and semantics as `Stream::poll_next`,
+    /// so the watcher can easily be adapted into a stream.
+    pub fn poll_next_version(&mut self, cx: &mut Context<'_>) -> Poll<Option<(Version, Arc<T>)>> {
+        let rcu = match self.rcu.upgrade() {
+            Some(rcu) => rcu,
+            None => return Poll::Ready(None),
+        };
+        {
+            // We register before we check the version, so that an update
+            // made in between cannot be missed.
+            let mut wakers = rcu.inner.wakers.lock().unwrap();
+            if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+        }
+        let grace = Grace::new();
+        let current = rcu.read(&grace);
+        if current.version() > self.seen {
+            self.seen = current.version();
+            Poll::Ready(Some((self.seen, current.to_arc())))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<T: ?Sized> Clone for RcuWatcher<T> {
+    fn clone(&self) -> Self {
+        RcuWatcher {
+            rcu: self.rcu.clone(),
+            seen: self.seen,
+        }
+    }
+}
+
+/// The future returned by [`RcuWatcher::changed`]
+pub struct Changed<'a, T: ?Sized> {
+    watcher: &'a mut RcuWatcher<T>,
+}
+impl<'a, T: ?Sized> Future for Changed<'a, T> {
+    type Output = Option<Arc<T>>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.watcher
+            .poll_next_version(cx)
+            .map(|next| next.map(|(_, value)| value))
+    }
+}
+
+/// The future returned by [`RcuWatcher::next_version`]
+pub struct NextVersion<'a, T: ?Sized> {
+    watcher: &'a mut RcuWatcher<T>,
+}
+impl<'a, T: ?Sized> Future for NextVersion<'a, T> {
+    type Output = Option<(Version, Arc<T>)>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.watcher.poll_next_version(cx)
+    }
+}
+
+/// A value computed from an [`Rcu`], which is only recomputed when needed
+///
+/// This is created by [`Rcu::derive`], and may itself be derived from to
+/// create a chain of computations.  The version of a `Derived` value is the
+/// version of the `Rcu` at the start of the chain that it was computed from.
+/// Cloning a `Derived` shares the cached value.
+pub struct Derived<U: ?Sized> {
+    inner: Arc<DerivedInner<U>>,
+}
+
+/// The most values a [`Derived`] keeps for graces that cannot see the
+/// latest transaction
+const STALE_VALUES: usize = 4;
+
+type SourceVersion = Box<dyn Fn(&Grace) -> Version + Send + Sync>;
+type Compute<U> = Box<dyn Fn(&Grace) -> (Version, Arc<U>) + Send + Sync>;
+
+struct DerivedInner<U: ?Sized> {
+    cache: Rcu<U>,
+    // Held while recomputing, so that we only compute once per version.
+    recomputing: Mutex<()>,
+    // Values computed for graces that cannot see the latest transaction,
+    // which are older than anything in the cache.
+    stale: Mutex<Vec<Box<Node<U>>>>,
+    source_version: SourceVersion,
+    compute: Compute<U>,
+}
+
+/// Something that a [`Derived`] value can be computed from
+trait Source: Send + Sync + 'static {
+    type Target: ?Sized;
+    fn read_source<'a>(&'a self, grace: &'a Grace) -> RcuGuard<'a, Self::Target>;
+}
+impl<T: ?Sized + Send + Sync + 'static> Source for Rcu<T> {
+    type Target = T;
+    fn read_source<'a>(&'a self, grace: &'a Grace) -> RcuGuard<'a, T> {
+        self.read(grace)
+    }
+}
+impl<U: ?Sized + Send + Sync + 'static> Source for Derived<U> {
+    type Target = U;
+    fn read_source<'a>(&'a self, grace: &'a Grace) -> RcuGuard<'a, U> {
+        self.read(grace)
+    }
+}
+
+impl<U: ?Sized> Clone for Derived<U> {
+    fn clone(&self) -> Self {
+        Derived {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<U: Send + Sync + 'static> Derived<U> {
+    fn new<S: Source>(source: S, f: impl Fn(&S::Target) -> U + Send + Sync + 'static) -> Self {
+        let source = Arc::new(source);
+        let compute: Compute<U> = {
+            let source = source.clone();
+            Box::new(move |grace| {
+                let value = source.read_source(grace);
+                (value.version(), Arc::new(f(&value)))
+            })
+        };
+        let (version, value) = compute(&Grace::new());
+        Derived {
+            inner: Arc::new(DerivedInner {
+                cache: Rcu {
+                    inner: Arc::new(Inner::new(value, version)),
+                },
+                recomputing: Mutex::new(()),
+                stale: Mutex::new(Vec::new()),
+                source_version: Box::new(move |grace| source.read_source(grace).version()),
+                compute,
+            }),
+        }
+    }
+}
+
+impl<U: ?Sized + Send + Sync + 'static> Derived<U> {
+    /// Measure the values we retire with `weigh`, just like
+    /// [`Rcu::with_weigher`]
+    pub fn with_weigher(self, weigh: fn(&U) -> usize) -> Self {
+        *self.inner.cache.inner.weigh.lock().unwrap() = weigh;
+        self
+    }
+    /// Read the derived value, recomputing it if the source has changed
+    ///
+    /// If the value needs recomputing, this blocks while it is computed, so
+    /// that concurrent readers share a single computation.  A `Grace` that
+    /// cannot see the latest [`transaction`] computes the value from what it
+    /// sees, which we keep for other reads of that same version.
+    pub fn read<'a, 'b: 'a>(&'b self, grace: &'a Grace) -> RcuGuard<'a, U> {
+        let inner = &self.inner;
+        if let Some(cached) = self.cached(grace) {
+            return cached;
+        }
+        // The lock protects no data, so there is no harm in ignoring a panic
+        // in some other computation.
+        let _lock = inner.recomputing.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(cached) = self.cached(grace) {
+            return cached;
+        }
+        let seen = (inner.source_version)(grace);
+        let mut stale = inner.stale.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(node) = stale.iter().find(|node| node.version == seen) {
+            // Stale values are only freed once every grace that might be
+            // reading them is over.
+            let node: &'a Node<U> = unsafe { &*(&**node as *const Node<U>) };
+            return RcuGuard {
+                node,
+                ptr: &node.value,
+            };
+        }
+        let (version, value) = (inner.compute)(grace);
+        if version > inner.cache.read_current(grace).version() {
+            // Readers never wait for the budget, even when they publish.
+            let period = source_of_grace().0.lock().unwrap();
+            inner
+                .cache
+                .publish_with(period, value, |_| Ok(version))
+                .ok();
+            // A snapshot would not see the node we just published, but its
+            // value comes from what the snapshot sees.
+            inner.cache.read_current(grace)
+        } else {
+            // This grace began before a transaction that changed the source,
+            // so the value we computed is only for graces as old as this one.
+            let node = Box::new(Node {
+                value,
+                version,
+                visible_from: 0,
+                published: 0,
+                prev: std::ptr::null(),
+            });
+            let ptr: *const Node<U> = &*node;
+            stale.push(node);
+            if stale.len() > STALE_VALUES {
+                let oldest = stale.remove(0);
+                let weigh = *inner.cache.inner.weigh.lock().unwrap();
+                let oldest = Box::new(Retired {
+                    bytes: weigh(&oldest.value),
+                    node: Some(oldest),
+                    home: Arc::downgrade(&inner.cache.inner),
+                });
+                let mut period = source_of_grace().0.lock().unwrap();
+                // A grace that is open now could still be reading it.
+                period.epoch += 1;
+                period.retire(vec![oldest]);
+            }
+            let node: &'a Node<U> = unsafe { &*ptr };
+            RcuGuard {
+                node,
+                ptr: &node.value,
+            }
+        }
+    }
+    /// The cached value, if it was computed from the version of the source
+    /// that `grace` sees
+    fn cached<'a>(&'a self, grace: &'a Grace) -> Option<RcuGuard<'a, U>> {
+        let seen = (self.inner.source_version)(grace);
+        let cached = self.inner.cache.read(grace);
+        if cached.version() == seen {
+            return Some(cached);
+        }
+        // A snapshot does not see values cached after it began, but the
+        // current one will do if it comes from the same version.
+        let current = self.inner.cache.read_current(grace);
+        (current.version() == seen).then_some(current)
+    }
+    /// Obtain an owned reference to the current derived value
+    pub fn load_full(&self) -> Arc<U> {
+        self.read(&Grace::new()).to_arc()
+    }
+    /// The version of the source that the current derived value comes from
+    pub fn version(&self) -> Version {
+        self.read(&Grace::new()).version()
+    }
+    /// Compute a value from this one, recomputing only when it changes
+    ///
+    /// The new value is recomputed whenever this one is.
+    /// ```
+    /// use rcu_clean::graceful::Rcu;
+    /// let v = Rcu::new(2);
+    /// let squared = v.derive(|v| v * v);
+    /// let plus_one = squared.derive(|v| v + 1);
+    /// v.update(|v| *v = 3);
+    /// assert_eq!(10, *plus_one.load_full());
+    /// ```
+    pub fn derive<V: Send + Sync + 'static>(
+        &self,
+        f: impl Fn(&U) -> V + Send + Sync + 'static,
+    ) -> Derived<V> {
+        Derived::new(self.clone(), f)
+    }
+}
 
-        // Now we take the grace-period lock before doing our update.  Since we
-        // have just source of grace, this means no other critical update
-        // sections are ongoing, and all updates are totally ordered.
-        //
-        // It also means that no one can start a new grace period while we're
-        // working on this change.
-        let mut lock = GRACE.get().unwrap().0.lock().unwrap();
+/// A guard for modifying the contents of an [`Rcu`]
+///
+/// This is created by [`Rcu::write`], and publishes the modified value when
+/// it is dropped.
+pub struct RcuWriteGuard<'a, T: RcuClone + Send + Sync + 'static> {
+    rcu: &'a Rcu<T>,
+    new: Option<Arc<T>>,
+}
+impl<'a, T: RcuClone + Send + Sync + 'static> RcuWriteGuard<'a, T> {
+    /// Discard the modifications without publishing them
+    /// ```
+    /// let v = rcu_clean::graceful::Rcu::new(1);
+    /// let mut w = v.write();
+    /// *w = 2;
+    /// rcu_clean::graceful::RcuWriteGuard::abort(w);
+    /// assert_eq!(1, *v.load_full());
+    /// ```
+    pub fn abort(mut orig: Self) {
+        orig.new = None;
+    }
+}
+impl<'a, T: RcuClone + Send + Sync + 'static> Deref for RcuWriteGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.new.as_ref().unwrap()
+    }
+}
+impl<'a, T: RcuClone + Send + Sync + 'static> DerefMut for RcuWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        private(self.new.as_mut().unwrap())
+    }
+}
+impl<'a, T: RcuClone + Send + Sync + 'static> Drop for RcuWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        if let Some(new) = self.new.take() {
+            if !std::thread::panicking() {
+                self.rcu.publish(new, None).ok();
+            }
+        }
+    }
+}
 
-        let mut vec_lock = lock.lock().unwrap();
+/// Update several [`Rcu`]s together
+///
+/// Your closure makes updates through the [`Transaction`], which are all
+/// published at once when it returns.  A reader will either see all of the
+/// new values or none of them: each `Grace` sees a transaction only if it
+/// began after the transaction was committed, so readers holding an older
+/// `Grace` continue to see the old values.  If your closure panics, nothing
+/// is published.
+///
+/// As with [`Rcu::update`], simultaneous updates to the same pointers are
+/// safe but not recommended, since the transaction may then overwrite
+/// changes made while it was running.
+/// ```
+/// use rcu_clean::graceful::{transaction, Grace, Rcu};
+/// let routes = Rcu::new(vec![("a", 1)]);
+/// let reverse = Rcu::new(vec![(1, "a")]);
+/// let grace = Grace::new();
+/// transaction(|tx| {
+///     tx.update(&routes, |r| r.push(("b", 2)));
+///     tx.update(&reverse, |r| r.push((2, "b")));
+/// });
+/// // A grace that was open during the transaction sees neither change.
+/// assert_eq!(1, routes.read(&grace).len());
+/// assert_eq!(1, reverse.read(&grace).len());
+/// let grace = Grace::new();
+/// assert_eq!(2, routes.read(&grace).len());
+/// assert_eq!(2, reverse.read(&grace).len());
+/// ```
+pub fn transaction<R>(f: impl FnOnce(&mut Transaction) -> R) -> R {
+    check_budget();
+    let mut tx = Transaction {
+        pending: Vec::new(),
+    };
+    let result = f(&mut tx);
+    tx.commit();
+    result
+}
 
-        // First we store the old value to be freed.
-        let old = self.0.swap(Arc::into_raw(new) as *mut T, Ordering::Release);
-        vec_lock.push(unsafe { Arc::from_raw(old) });
+/// A set of updates that will be published together
+///
+/// This is created by [`transaction`].
+pub struct Transaction {
+    pending: Vec<Box<dyn Pending>>,
+}
 
-        let next_grace = Arc::new(Mutex::new(Vec::new()));
-        // The old grace period will depend on the new grace period, so the
-        // freeing happens in the correct order.
-        vec_lock.push(Arc::new(next_grace.clone()));
-        drop(vec_lock);
+impl Transaction {
+    /// Modify the contents of an `Rcu` as part of this transaction
+    ///
+    /// The closure modifies a private copy of the value, which will be
+    /// published when the transaction is committed.  Updating the same `Rcu`
+    /// twice modifies the same copy.
+    pub fn update<T: RcuClone + Send + Sync + 'static>(
+        &mut self,
+        rcu: &Rcu<T>,
+        f: impl FnOnce(&mut T),
+    ) {
+        for p in self.pending.iter_mut() {
+            if let Some(p) = p.as_any().downcast_mut::<PendingUpdate<T>>() {
+                if Arc::ptr_eq(&p.rcu.inner, &rcu.inner) {
+                    f(private(p.new.as_mut().unwrap()));
+                    return;
+                }
+            }
+        }
+        let mut new = rcu.copy(&rcu.read(&Grace::new()));
+        f(private(&mut new));
+        self.pending.push(Box::new(PendingUpdate {
+            rcu: rcu.share(),
+            new: Some(new),
+        }));
+    }
+    fn commit(mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let mut period = within_budget(source_of_grace().0.lock().unwrap());
+        period.epoch += 1;
+        let epoch = period.epoch;
+        let old = self.pending.iter_mut().map(|p| p.install(epoch)).collect();
+        period.retire(old);
+        reclaim(period);
+        for p in self.pending.iter() {
+            p.wake_watchers();
+        }
+    }
+}
 
-        // Now we update the SourceOfGrace, which should always hold an empty
-        // vector, so that everything that does need to get freed *will* get
-        // freed.
-        *lock = next_grace;
+/// An update waiting for its transaction to be committed
+trait Pending {
+    fn as_any(&mut self) -> &mut dyn Any;
+    /// Publish the new value in `epoch`, returning the old node
+    fn install(&mut self, epoch: u64) -> Box<dyn Expiring>;
+    fn wake_watchers(&self);
+}
+
+struct PendingUpdate<T> {
+    rcu: Rcu<T>,
+    new: Option<Arc<T>>,
+}
+
+impl<T: Send + Sync + 'static> Pending for PendingUpdate<T> {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn install(&mut self, epoch: u64) -> Box<dyn Expiring> {
+        let new = self.new.take().unwrap();
+        let version = self.rcu.current_version().next();
+        self.rcu.install(new, version, epoch, true)
+    }
+    fn wake_watchers(&self) {
+        self.rcu.wake_watchers();
     }
 }
 
@@ -139,9 +1430,50 @@ static GRACE: OnceCell<SourceOfGrace> = OnceCell::new();
 /// that no reader ends up reading after free.  Creating a `Grace` is
 /// relatively expensive, so ideally you'd like to create a single `Grace` and
 /// using it for a number of reads.
-#[derive(Clone)]
 pub struct Grace {
-    _to_free: Arc<Mutex<Vec<Arc<dyn Send + Sync>>>>,
+    epoch: u64,
+    snapshot: bool,
+    // Where this grace was created, with the `grace-backtrace` feature
+    backtrace: Option<Arc<Backtrace>>,
+}
+
+/// The number of snapshot graces that are open
+static SNAPSHOTS: AtomicUsize = AtomicUsize::new(0);
+
+impl Clone for Grace {
+    fn clone(&self) -> Self {
+        if self.snapshot {
+            SNAPSHOTS.fetch_add(1, Ordering::SeqCst);
+        }
+        let mut period = source_of_grace().0.lock().unwrap();
+        let readers = period.readers.entry(self.epoch).or_default();
+        readers.add(&self.backtrace);
+        Grace {
+            epoch: self.epoch,
+            snapshot: self.snapshot,
+            backtrace: self.backtrace.clone(),
+        }
+    }
+}
+
+impl Drop for Grace {
+    fn drop(&mut self) {
+        if self.snapshot {
+            SNAPSHOTS.fetch_sub(1, Ordering::SeqCst);
+        }
+        let mut period = source_of_grace().0.lock().unwrap();
+        let readers = period.readers.get_mut(&self.epoch).unwrap();
+        if readers.remove(&self.backtrace) {
+            period.readers.remove(&self.epoch);
+            reclaim(period);
+        }
+    }
+}
+
+impl Default for Grace {
+    fn default() -> Self {
+        Grace::new()
+    }
 }
 
 impl Grace {
@@ -151,29 +1483,799 @@ impl Grace {
     /// confident that no Rcu data that was accessible to these reads will be
     /// freed until after this `Grace` has been dropped.
     pub fn new() -> Grace {
+        let backtrace = if cfg!(feature = "grace-backtrace") {
+            Some(Arc::new(Backtrace::force_capture()))
+        } else {
+            None
+        };
+        let mut period = source_of_grace().0.lock().unwrap();
+        let epoch = period.epoch;
+        period.readers.entry(epoch).or_default().add(&backtrace);
         Grace {
-            _to_free: GRACE
-                .get_or_init(|| SourceOfGrace(Mutex::new(Arc::new(Mutex::new(Vec::new())))))
-                .0
-                .lock()
-                .unwrap()
-                .clone(),
+            epoch,
+            snapshot: false,
+            backtrace,
+        }
+    }
+    /// Create a grace period that reads a consistent snapshot
+    ///
+    /// Every read made with this `Grace` returns the value that was current
+    /// when the snapshot was taken, so reads of several `Rcu`s never see a
+    /// mix of older and newer values.  Reading a value that has been updated
+    /// since the snapshot needs to look back through every version that was
+    /// published since, so its cost grows with the number of updates.  Keep
+    /// snapshots of often-updated values short.
+    ///
+    /// A [`Derived`] value read with a snapshot is computed from the source
+    /// values that the snapshot sees, so it is consistent with them too.
+    ///
+    /// A snapshot cannot begin while [`Rcu::update_mut`] is modifying a value
+    /// in place, so this waits for any such modification to finish.
+    /// ```
+    /// use rcu_clean::graceful::{Grace, Rcu};
+    /// let a = Rcu::new(1);
+    /// let b = Rcu::new(1);
+    /// let snapshot = Grace::snapshot();
+    /// let grace = Grace::new();
+    /// a.update(|a| *a = 2);
+    /// b.update(|b| *b = 2);
+    /// assert_eq!((1, 1), (*a.read(&snapshot), *b.read(&snapshot)));
+    /// assert_eq!((2, 2), (*a.read(&grace), *b.read(&grace)));
+    /// ```
+    pub fn snapshot() -> Grace {
+        let mut period = source_of_grace().0.lock().unwrap();
+        while period.in_place > 0 {
+            period = IN_PLACE_DONE.wait(period).unwrap();
+        }
+        // Once this is set, `update_mut` will copy rather than modify in
+        // place.
+        SNAPSHOTS.fetch_add(1, Ordering::SeqCst);
+        drop(period);
+        let mut grace = Grace::new();
+        grace.snapshot = true;
+        grace
+    }
+    /// Move this grace into the current grace period
+    ///
+    /// A long-running reader can call this between units of work, so that
+    /// values which were retired while it was working can be freed, without
+    /// the cost of dropping this grace and creating a new one.  Since this
+    /// takes `&mut self`, no reads made with the old period can still be
+    /// held.  A snapshot grace moves to a snapshot of the current values.
+    /// ```
+    /// use rcu_clean::graceful::{Grace, Rcu};
+    /// let v = Rcu::new(1);
+    /// let mut grace = Grace::new();
+    /// v.update(|v| *v += 1);
+    /// assert_eq!(2, *v.read(&grace));
+    /// grace.refresh(); // the old value may now be freed
+    /// assert_eq!(2, *v.read(&grace));
+    /// ```
+    pub fn refresh(&mut self) {
+        let mut period = source_of_grace().0.lock().unwrap();
+        let epoch = period.epoch;
+        if epoch == self.epoch {
+            return;
+        }
+        period
+            .readers
+            .entry(epoch)
+            .or_default()
+            .add(&self.backtrace);
+        let old = std::mem::replace(&mut self.epoch, epoch);
+        let readers = period.readers.get_mut(&old).unwrap();
+        if readers.remove(&self.backtrace) {
+            period.readers.remove(&old);
+            reclaim(period);
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT_GRACE: Cell<Option<*const Grace>> = const { Cell::new(None) };
+}
+
+/// Run `f` with this thread's current grace, creating one if there is none
+///
+/// The outermost call creates a [`Grace`] that lasts until it returns, and
+/// any calls nested within it reuse that same grace, which costs next to
+/// nothing.  This lets code deep in a call stack read an [`Rcu`] without
+/// needing a `&Grace` passed down to it.  Keep in mind that nothing retired
+/// while the outermost call is running can be freed until it returns.
+/// ```
+/// use rcu_clean::graceful::{with_grace, Rcu};
+/// fn total(v: &Rcu<Vec<u32>>) -> u32 {
+///     with_grace(|grace| v.read(grace).iter().sum())
+/// }
+/// let v = Rcu::new(vec![1, 2, 3]);
+/// with_grace(|grace| {
+///     let first = v.read(grace);
+///     v.update(|v| v.push(4));
+///     assert_eq!(10, total(&v)); // the inner call shares our grace
+///     assert_eq!(3, first.len());
+/// });
+/// ```
+pub fn with_grace<R>(f: impl FnOnce(&Grace) -> R) -> R {
+    if let Some(grace) = CURRENT_GRACE.with(|current| current.get()) {
+        // The outermost call keeps this grace alive until it returns.
+        return f(unsafe { &*grace });
+    }
+    /// Forget the current grace even if `f` panics
+    struct Reset;
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            CURRENT_GRACE.with(|current| current.set(None));
+        }
+    }
+    let grace = Grace::new();
+    CURRENT_GRACE.with(|current| current.set(Some(&grace as *const Grace)));
+    let _reset = Reset;
+    f(&grace)
+}
+
+/// A grace that an async task can hold across `.await`
+///
+/// An `AsyncGrace` is `Send + 'static`, so it can live in a spawned task or
+/// in a struct that a future owns, and it derefs to a [`Grace`] for reads.
+/// A task can sit at an `.await` for an unbounded time, so the grace it
+/// holds bounds reclamation only as well as the task behaves: values retired
+/// after the grace last began are kept until the task calls
+/// [`AsyncGrace::resume`] or drops the grace.  A task that resumes after
+/// every `.await`, and holds no guards across one, therefore delays
+/// reclamation by no more than its longest single wait, and [`on_stall`]
+/// will tell you about any task that does not.
+/// ```
+/// use rcu_clean::graceful::{AsyncGrace, Rcu};
+/// fn assert_send_static<T: Send + 'static>(_: &T) {}
+/// let v = Rcu::new(1);
+/// let mut grace = AsyncGrace::new();
+/// assert_send_static(&grace);
+/// let seen = v.read(&grace).version();
+/// // ... some_io().await ...
+/// grace.resume();
+/// assert_eq!(1, *grace.revalidate(&v, seen).unwrap());
+/// v.update(|v| *v = 2);
+/// grace.resume();
+/// assert!(grace.revalidate(&v, seen).is_err());
+/// ```
+pub struct AsyncGrace {
+    grace: Grace,
+    began: Instant,
+}
+
+impl AsyncGrace {
+    /// Begin a grace that may be held across `.await`
+    pub fn new() -> AsyncGrace {
+        AsyncGrace {
+            grace: Grace::new(),
+            began: Instant::now(),
+        }
+    }
+    /// Move into the current grace period after an `.await`
+    ///
+    /// This releases whatever was retired while we waited, so call it each
+    /// time the task resumes.  Any guards read before the `.await` must be
+    /// dropped first, and read again (perhaps with
+    /// [`AsyncGrace::revalidate`]) afterwards.
+    pub fn resume(&mut self) {
+        self.grace.refresh();
+        self.began = Instant::now();
+    }
+    /// How long since this grace began, or last resumed
+    ///
+    /// This is how far back it is holding up reclamation.
+    pub fn age(&self) -> Duration {
+        self.began.elapsed()
+    }
+    /// Read `rcu` again, provided it has not changed from version `seen`
+    ///
+    /// This lets a task that read a value before an `.await` check that its
+    /// earlier work is still valid, without holding a guard across the
+    /// `.await`.
+    pub fn revalidate<'a, T: ?Sized>(
+        &'a self,
+        rcu: &'a Rcu<T>,
+        seen: Version,
+    ) -> Result<RcuGuard<'a, T>, Conflict> {
+        let guard = rcu.read(&self.grace);
+        if guard.version() == seen {
+            Ok(guard)
+        } else {
+            Err(Conflict {
+                expected: seen,
+                actual: guard.version(),
+            })
         }
     }
 }
 
-struct SourceOfGrace(Mutex<Arc<Mutex<Vec<Arc<dyn Send + Sync>>>>>);
+impl Default for AsyncGrace {
+    fn default() -> Self {
+        AsyncGrace::new()
+    }
+}
+
+impl Deref for AsyncGrace {
+    type Target = Grace;
+    fn deref(&self) -> &Grace {
+        &self.grace
+    }
+}
+
+/// Values that have been retired, but which a grace may still be reading
+type Garbage = Vec<Box<dyn Expiring>>;
+
+struct SourceOfGrace(Mutex<Period>);
+
+/// The current grace period, along with the graces that are still open and
+/// the values they may be reading
+struct Period {
+    // The number of times values have been published, which serves as a
+    // global clock.
+    epoch: u64,
+    // The open graces, by the epoch in which they began
+    readers: BTreeMap<u64, Readers>,
+    garbage: EpochQueue<Box<dyn Expiring>>,
+    retained_values: usize,
+    retained_bytes: usize,
+    stall: Option<StallCheck>,
+    budget: Option<(usize, Backpressure)>,
+    // The number of `update_mut` calls modifying a value in place, during
+    // which no snapshot may begin
+    in_place: usize,
+}
+
+/// The graces that began in one epoch
+struct Readers {
+    count: usize,
+    // When the first of them began
+    since: Instant,
+    // Where they began, if we are capturing backtraces
+    backtraces: Vec<Arc<Backtrace>>,
+}
+
+impl Readers {
+    fn add(&mut self, backtrace: &Option<Arc<Backtrace>>) {
+        self.count += 1;
+        self.backtraces.extend(backtrace.clone());
+    }
+    /// Note that a grace is over, returning true if it was the last one
+    fn remove(&mut self, backtrace: &Option<Arc<Backtrace>>) -> bool {
+        if let Some(backtrace) = backtrace {
+            let i = self
+                .backtraces
+                .iter()
+                .position(|b| Arc::ptr_eq(b, backtrace));
+            self.backtraces.swap_remove(i.unwrap());
+        }
+        self.count -= 1;
+        self.count == 0
+    }
+}
+
+impl Default for Readers {
+    fn default() -> Self {
+        Readers {
+            count: 0,
+            since: Instant::now(),
+            backtraces: Vec::new(),
+        }
+    }
+}
+
+impl Period {
+    /// Retire values that graces which began before this epoch may still be
+    /// using
+    fn retire(&mut self, old: Garbage) {
+        self.retained_values += old.len();
+        self.retained_bytes += old.iter().map(|v| v.bytes()).sum::<usize>();
+        self.garbage.retire(self.epoch, old);
+    }
+    /// Take a batch of retired values that no open grace could be reading
+    fn expired(&mut self) -> Garbage {
+        let expired = self.garbage.expired(&self.readers);
+        self.retained_values -= expired.len();
+        self.retained_bytes -= expired.iter().map(|v| v.bytes()).sum::<usize>();
+        expired
+    }
+    fn over_budget(&self) -> Option<OverBudget> {
+        match self.budget {
+            Some((budget, _)) if self.retained_bytes > budget => Some(OverBudget {
+                retained_bytes: self.retained_bytes,
+                budget,
+            }),
+            _ => None,
+        }
+    }
+    /// Check whether we have stalled, returning a warning for the callback
+    /// the first time we notice
+    fn stalled(&mut self) -> Option<(StallCallback, StallWarning)> {
+        let check = self.stall.as_mut()?;
+        let oldest = self.readers.values().next();
+        let open = oldest.map(|r| r.since.elapsed()).unwrap_or_default();
+        let bytes = self.retained_bytes;
+        let stalled = self.retained_values > 0
+            && (check.max_open.is_some_and(|max| open > max)
+                || check.max_bytes.is_some_and(|max| bytes > max));
+        if !stalled {
+            check.reported = false;
+            return None;
+        }
+        if std::mem::replace(&mut check.reported, true) {
+            return None;
+        }
+        let warning = StallWarning {
+            open,
+            retained_values: self.retained_values,
+            retained_bytes: self.retained_bytes,
+            backtrace: oldest.and_then(|r| r.backtraces.first().cloned()),
+        };
+        Some((check.callback.clone(), warning))
+    }
+}
+
+/// Free every retired value that no open grace could be reading
+///
+/// We release the lock while freeing, and take the values a batch at a time,
+/// so that a long backlog is freed without recursion, and without keeping
+/// other threads waiting.  Once we are done, we check for a stall.
+fn reclaim(mut period: MutexGuard<'_, Period>) {
+    loop {
+        let expired = period.expired();
+        if expired.is_empty() {
+            let stalled = period.stalled();
+            drop(period);
+            if let Some((callback, warning)) = stalled {
+                callback(&warning);
+            }
+            return;
+        }
+        drop(period);
+        UNDER_BUDGET.notify_all();
+        dispose(expired);
+        period = source_of_grace().0.lock().unwrap();
+    }
+}
+
+/// Wait until the retained values are within the budget, with
+/// [`Backpressure::Block`]
+fn within_budget(mut period: MutexGuard<'_, Period>) -> MutexGuard<'_, Period> {
+    while period.over_budget().is_some() {
+        if let Some((_, Backpressure::Fail)) = period.budget {
+            // We checked when the update began, in `check_budget`.
+            break;
+        }
+        period = UNDER_BUDGET.wait(period).unwrap();
+    }
+    period
+}
+
+/// Panic if the retained values exceed the budget, with
+/// [`Backpressure::Fail`]
+///
+/// We check when an update begins, on the thread that asked for it, because
+/// publishing may happen in a `Drop` or on a thread that is combining other
+/// threads' updates, where a panic would be no help to anyone.
+fn check_budget() {
+    let period = source_of_grace().0.lock().unwrap();
+    if let (Some(over), Some((_, Backpressure::Fail))) = (period.over_budget(), period.budget) {
+        // Release the lock first, so that we do not poison it.
+        drop(period);
+        panic!("{}", over);
+    }
+}
+
+/// Notified whenever retained values are freed, for [`Backpressure::Block`]
+static UNDER_BUDGET: Condvar = Condvar::new();
+
+/// Notified when no [`Rcu::update_mut`] is modifying a value in place, for
+/// [`Grace::snapshot`]
+static IN_PLACE_DONE: Condvar = Condvar::new();
+
+/// An [`Rcu::update_mut`] that is modifying a value in place
+struct InPlace;
+
+impl Drop for InPlace {
+    fn drop(&mut self) {
+        let mut period = source_of_grace().0.lock().unwrap();
+        period.in_place -= 1;
+        if period.in_place == 0 {
+            IN_PLACE_DONE.notify_all();
+        }
+    }
+}
+
+type StallCallback = Arc<dyn Fn(&StallWarning) + Send + Sync>;
+
+/// The limits set by [`on_stall`]
+struct StallCheck {
+    max_open: Option<Duration>,
+    max_bytes: Option<usize>,
+    callback: StallCallback,
+    // Whether we have already reported the current stall
+    reported: bool,
+}
+
+/// Call `callback` when graces hold on to old values for too long
+///
+/// A `Grace` that is never dropped keeps every value that is retired after
+/// it began, so a forgotten one silently accumulates garbage.  Whenever a
+/// value is retired, we check whether the oldest open grace that is holding
+/// on to values has been open for longer than `max_open`, or whether the
+/// values retained add up to more than `max_bytes` (as measured by
+/// [`Rcu::with_weigher`]).  If so, we call `callback`, just once until
+/// things are back to normal.
+///
+/// With the `grace-backtrace` feature, every `Grace` captures a backtrace
+/// where it was created, so that the warning can show you the culprit.
+/// This is expensive, so it is only meant for debugging.
+/// ```
+/// use rcu_clean::graceful::{on_stall, Grace, Rcu};
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+/// on_stall(None, Some(1000), |warning| {
+///     eprintln!("{} old values are being retained", warning.retained_values);
+///     WARNINGS.fetch_add(1, Ordering::Relaxed);
+/// });
+/// let v = Rcu::new([0u8; 100]);
+/// let forgotten = Grace::new();
+/// for i in 0..20 {
+///     v.update(|v| v[0] = i);
+/// }
+/// assert_eq!(1, WARNINGS.load(Ordering::Relaxed));
+/// ```
+pub fn on_stall(
+    max_open: Option<Duration>,
+    max_bytes: Option<usize>,
+    callback: impl Fn(&StallWarning) + Send + Sync + 'static,
+) {
+    source_of_grace().0.lock().unwrap().stall = Some(StallCheck {
+        max_open,
+        max_bytes,
+        callback: Arc::new(callback),
+        reported: false,
+    });
+}
+
+/// What [`on_stall`] tells you about a stall
+#[derive(Debug)]
+pub struct StallWarning {
+    /// How long the oldest grace that is retaining values has been open
+    pub open: Duration,
+    /// The number of old values that are being retained
+    pub retained_values: usize,
+    /// The size of the old values, as measured by [`Rcu::with_weigher`]
+    pub retained_bytes: usize,
+    /// Where the oldest grace was created, with the `grace-backtrace` feature
+    pub backtrace: Option<Arc<Backtrace>>,
+}
+
+/// What an update does when retained values exceed the budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait until enough old values have been freed
+    Block,
+    /// Panic, so that a leaked `Grace` is noticed right away
+    ///
+    /// The panic comes from the call that begins an update, such as
+    /// [`Rcu::update`], [`Rcu::write`] or [`Rcu::enqueue_update`], on the
+    /// thread that made it.  An update that has already begun is published
+    /// regardless, so dropping an [`RcuWriteGuard`] never panics, and nor
+    /// does a thread that is combining enqueued updates.
+    Fail,
+}
+
+/// Limit the size of old values that graces may retain
+///
+/// Once the retained values (as measured by [`Rcu::with_weigher`]) add up
+/// to more than `budget` bytes, updates either block until graces are
+/// dropped and values are freed, or panic, depending on `backpressure`.
+/// [`Rcu::try_update`] fails rather than doing either.  A budget of `None`
+/// removes the limit.
+///
+/// Be careful with [`Backpressure::Block`]: a thread that blocks while it
+/// holds the `Grace` that is retaining the values will wait forever.
+/// Reading a [`Derived`] value never waits, even when it recomputes.
+pub fn set_budget(budget: Option<usize>, backpressure: Backpressure) {
+    let mut period = source_of_grace().0.lock().unwrap();
+    period.budget = budget.map(|b| (b, backpressure));
+    drop(period);
+    // Blocked updates may be within a new budget.
+    UNDER_BUDGET.notify_all();
+}
+
+/// The error when retained values exceed the budget set by [`set_budget`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OverBudget {
+    /// The size of the old values that are being retained
+    pub retained_bytes: usize,
+    /// The budget they exceed
+    pub budget: usize,
+}
+
+impl std::fmt::Display for OverBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "graces are retaining {} bytes of old values, over the budget of {}",
+            self.retained_bytes, self.budget
+        )
+    }
+}
+
+impl std::error::Error for OverBudget {}
+
+/// Free values, on the [`ReclaimerThread`] if there is one
+fn dispose(values: Garbage) {
+    if !RECLAIMING.load(Ordering::Acquire) {
+        return;
+    }
+    // Values that expire on the reclaimer thread are freed right there.
+    if ON_RECLAIMER.try_with(|r| r.get()).unwrap_or(true) {
+        return;
+    }
+    if let Some(running) = RECLAIMER.lock().unwrap().as_ref() {
+        // If the reclaimer has stopped (because a destructor panicked), the
+        // values come back to us and are freed here.
+        running.sender.send(Reclaim::Values(values)).ok();
+    }
+}
+
+fn source_of_grace() -> &'static SourceOfGrace {
+    GRACE.get_or_init(|| {
+        SourceOfGrace(Mutex::new(Period {
+            epoch: 0,
+            readers: BTreeMap::new(),
+            garbage: EpochQueue::default(),
+            retained_values: 0,
+            retained_bytes: 0,
+            stall: None,
+            budget: None,
+            in_place: 0,
+        }))
+    })
+}
+
+/// Whether a [`ReclaimerThread`] is running, so that we can skip the lock
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+/// The reclaimer thread, if one is running
+static RECLAIMER: Mutex<Option<Running>> = Mutex::new(None);
+
+thread_local! {
+    static ON_RECLAIMER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The reclaimer thread, which is shared by every [`ReclaimerThread`] handle
+struct Running {
+    sender: mpsc::Sender<Reclaim>,
+    thread: std::thread::JoinHandle<()>,
+    handles: usize,
+}
+
+/// A message for the reclaimer thread
+enum Reclaim {
+    Values(Garbage),
+    Flush(mpsc::Sender<()>),
+}
+
+/// Free old values on a background thread, rather than on the thread that
+/// ends the last grace period that could read them
+///
+/// Normally when a `Grace` is dropped, any values that were retired while it
+/// was open (and that no other grace could be reading) are dropped right
+/// away.  That puts their destructors on the reader's thread, which could be
+/// a latency-critical one.  While the returned [`ReclaimerThread`] exists,
+/// those values are sent to a background thread to be dropped instead.
+///
+/// Values that an `Rcu` keeps as spares (see [`Rcu::with_spares`]) are not
+/// dropped at all, while a reclaimer given to [`Rcu::with_reclaimer`] is
+/// called on the background thread.
+///
+/// Calling this while a reclaimer is already running gives another handle to
+/// the same thread, which keeps running until every handle is dropped.
+/// ```
+/// use rcu_clean::graceful::{spawn_reclaimer, Grace, Rcu};
+/// use std::sync::Mutex;
+/// static DROPPED_ON: Mutex<Option<String>> = Mutex::new(None);
+/// #[derive(Clone)]
+/// struct Big(Vec<u8>);
+/// impl Drop for Big {
+///     fn drop(&mut self) {
+///         let thread = std::thread::current().name().map(String::from);
+///         *DROPPED_ON.lock().unwrap() = thread;
+///     }
+/// }
+/// let reclaimer = spawn_reclaimer();
+/// let v = Rcu::new(Big(vec![0; 1000]));
+/// let grace = Grace::new();
+/// v.update(|v| v.0.clear());
+/// drop(grace); // the old value is sent to the reclaimer thread
+/// reclaimer.flush();
+/// assert_eq!(Some("rcu-reclaimer"), DROPPED_ON.lock().unwrap().as_deref());
+/// ```
+pub fn spawn_reclaimer() -> ReclaimerThread {
+    let mut reclaimer = RECLAIMER.lock().unwrap();
+    if let Some(running) = reclaimer.as_mut() {
+        running.handles += 1;
+        return ReclaimerThread { _private: () };
+    }
+    let (sender, receiver) = mpsc::channel();
+    let thread = std::thread::Builder::new()
+        .name("rcu-reclaimer".to_string())
+        .spawn(move || {
+            ON_RECLAIMER.with(|r| r.set(true));
+            for message in receiver {
+                match message {
+                    Reclaim::Values(values) => drop(values),
+                    Reclaim::Flush(done) => {
+                        done.send(()).ok();
+                    }
+                }
+            }
+        })
+        .expect("failed to spawn the reclaimer thread");
+    *reclaimer = Some(Running {
+        sender,
+        thread,
+        handles: 1,
+    });
+    RECLAIMING.store(true, Ordering::Release);
+    ReclaimerThread { _private: () }
+}
+
+/// A handle to the thread started by [`spawn_reclaimer`]
+///
+/// When the last handle is dropped, the thread finishes dropping whatever
+/// it has been sent, and then exits, and we wait for it to do so.  After
+/// that, old values are again dropped by whichever thread ends their last
+/// grace period.
+#[must_use = "the reclaimer thread stops when the ReclaimerThread is dropped"]
+pub struct ReclaimerThread {
+    _private: (),
+}
+
+impl ReclaimerThread {
+    /// Wait until every value that has been sent to the reclaimer is dropped
+    ///
+    /// Values are only sent once no grace period could be reading them, so
+    /// this does not wait for values that a `Grace` is keeping alive.  This
+    /// is mostly useful in tests.
+    pub fn flush(&self) {
+        let sender = match RECLAIMER.lock().unwrap().as_ref() {
+            Some(running) => running.sender.clone(),
+            None => return,
+        };
+        let (done, finished) = mpsc::channel();
+        if sender.send(Reclaim::Flush(done)).is_ok() {
+            // This fails only if the reclaimer has stopped.
+            finished.recv().ok();
+        }
+    }
+}
+
+impl Drop for ReclaimerThread {
+    fn drop(&mut self) {
+        let mut reclaimer = RECLAIMER.lock().unwrap();
+        let running = reclaimer.as_mut().unwrap();
+        running.handles -= 1;
+        if running.handles > 0 {
+            return;
+        }
+        let running = reclaimer.take().unwrap();
+        RECLAIMING.store(false, Ordering::Release);
+        drop(reclaimer);
+        // Closing the channel tells the thread to stop once it has dropped
+        // everything it was sent.  If a destructor panicked, the thread has
+        // already stopped, and there is nothing more for us to do.
+        drop(running.sender);
+        running.thread.join().ok();
+    }
+}
 
 /// A reference to contents that are being read
 ///
 /// Note that the `RcuGuard` really just holds a reference, and its `Deref`
 /// costs no cpu instructions.
-pub struct RcuGuard<'a, T> {
+pub struct RcuGuard<'a, T: ?Sized> {
+    node: &'a Node<T>,
     ptr: &'a T,
 }
-impl<'a, T> Deref for RcuGuard<'a, T> {
+impl<'a, T: ?Sized> Deref for RcuGuard<'a, T> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
         self.ptr
     }
 }
+impl<'a, T: ?Sized> RcuGuard<'a, T> {
+    /// The version of the value we are reading
+    /// ```
+    /// use rcu_clean::graceful::{Grace, Rcu};
+    /// let v = Rcu::new(1);
+    /// let grace = Grace::new();
+    /// let old = v.read(&grace);
+    /// v.update(|v| *v = 2);
+    /// assert!(v.read(&grace).version() > old.version());
+    /// ```
+    pub fn version(&self) -> Version {
+        self.node.version
+    }
+    /// Obtain an owned reference to the value we are reading
+    ///
+    /// The `Arc` keeps this particular version alive after the grace period
+    /// is over, so you can hold onto it for as long as you like, or send it
+    /// to another thread.
+    /// ```
+    /// use rcu_clean::graceful::{Grace, Rcu};
+    /// let v = Rcu::new(String::from("hello"));
+    /// let owned = {
+    ///     let grace = Grace::new();
+    ///     v.read(&grace).to_arc()
+    /// };
+    /// v.update(|s| s.push_str(" world"));
+    /// assert_eq!("hello", &*owned);
+    /// ```
+    pub fn to_arc(&self) -> Arc<T> {
+        self.node.value.clone()
+    }
+    /// Make a guard for a component of the value we are reading
+    ///
+    /// This is an associated function that needs to be used as
+    /// `RcuGuard::map(...)`, so that it does not interfere with methods of
+    /// `T`.  This has the same semantics as `std::cell::Ref::map`, and the
+    /// resulting guard is bound by the same grace period.
+    /// ```
+    /// use rcu_clean::graceful::{Grace, Rcu, RcuGuard};
+    /// let v = Rcu::new((String::from("name"), 42));
+    /// let grace = Grace::new();
+    /// let name = RcuGuard::map(v.read(&grace), |v| v.0.as_str());
+    /// v.update(|v| v.0.push_str("-changed"));
+    /// assert_eq!("name", &*name);
+    /// ```
+    pub fn map<U: ?Sized, F: FnOnce(&T) -> &U>(orig: Self, f: F) -> MappedRcuGuard<'a, U> {
+        MappedRcuGuard { ptr: f(orig.ptr) }
+    }
+    /// Make a guard for an optional component of the value we are reading
+    ///
+    /// This has the same semantics as `std::cell::Ref::filter_map`.  If the
+    /// closure returns `None`, the original guard is handed back.
+    pub fn filter_map<U: ?Sized, F: FnOnce(&T) -> Option<&U>>(
+        orig: Self,
+        f: F,
+    ) -> Result<MappedRcuGuard<'a, U>, Self> {
+        match f(orig.ptr) {
+            Some(ptr) => Ok(MappedRcuGuard { ptr }),
+            None => Err(orig),
+        }
+    }
+}
+
+/// A reference to a component of contents that are being read
+///
+/// This is created by [`RcuGuard::map`], and like the `RcuGuard` it cannot
+/// outlive the grace period.
+pub struct MappedRcuGuard<'a, T: ?Sized> {
+    ptr: &'a T,
+}
+impl<'a, T: ?Sized> Deref for MappedRcuGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.ptr
+    }
+}
+impl<'a, T: ?Sized> MappedRcuGuard<'a, T> {
+    /// Make a guard for a component of this component
+    pub fn map<U: ?Sized, F: FnOnce(&T) -> &U>(orig: Self, f: F) -> MappedRcuGuard<'a, U> {
+        MappedRcuGuard { ptr: f(orig.ptr) }
+    }
+    /// Make a guard for an optional component of this component
+    pub fn filter_map<U: ?Sized, F: FnOnce(&T) -> Option<&U>>(
+        orig: Self,
+        f: F,
+    ) -> Result<MappedRcuGuard<'a, U>, Self> {
+        match f(orig.ptr) {
+            Some(ptr) => Ok(MappedRcuGuard { ptr }),
+            None => Err(orig),
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Rcu with grace periods for a single thread
+//!
+//! [`LocalRcu`] and [`LocalGrace`] work just like [`Rcu`](super::Rcu) and
+//! [`Grace`](super::Grace), but they are built on `Rc` and `Cell` rather
+//! than `Arc`, `Mutex` and atomics, and each thread keeps its own grace
+//! periods.  Neither type is `Send`, so the compiler will not let you use
+//! them from more than one thread.
+//! ```
+//! use rcu_clean::graceful::local::{LocalGrace, LocalRcu};
+//! let v = LocalRcu::new(vec![1, 2, 3]);
+//! let grace = LocalGrace::new();
+//! let old = v.read(&grace);
+//! v.update(|v| v.push(4));
+//! assert_eq!(3, old.len());
+//! assert_eq!(4, v.read(&grace).len());
+//! ```
+//! ```compile_fail
+//! use rcu_clean::graceful::local::LocalRcu;
+//! let v = LocalRcu::new(1);
+//! std::thread::spawn(move || v.update(|v| *v = 2));
+//! ```
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use crate::epochs::EpochQueue;
+use crate::RcuClone;
+
+/// The same bookkeeping as for [`Rcu`](super::Rcu), but for this thread only
+#[derive(Default)]
+struct Period {
+    epoch: u64,
+    // The number of open graces, by the epoch in which they began
+    readers: BTreeMap<u64, usize>,
+    // Old values, which may be freed once the graces that could be reading
+    // them are dropped
+    garbage: EpochQueue<Box<dyn Any>>,
+}
+
+thread_local! {
+    static PERIOD: RefCell<Period> = RefCell::default();
+}
+
+/// Free every retired value that no open grace on this thread could be
+/// reading
+fn reclaim() {
+    loop {
+        let expired = PERIOD.with(|period| {
+            let Period {
+                readers, garbage, ..
+            } = &mut *period.borrow_mut();
+            garbage.expired(readers)
+        });
+        if expired.is_empty() {
+            return;
+        }
+        // Dropping the values could run any code at all, including code that
+        // reads or updates other pointers, so we must not be borrowing the
+        // period.
+        drop(expired);
+    }
+}
+
+/// A grace period for reading [`LocalRcu`] pointers on this thread
+///
+/// No value that could be read with this grace is freed until it is
+/// dropped.
+pub struct LocalGrace {
+    epoch: u64,
+    // Our epoch only means something on the thread that created us.
+    _not_send: PhantomData<Rc<()>>,
+}
+
+impl LocalGrace {
+    /// Create a new grace period
+    pub fn new() -> LocalGrace {
+        let epoch = PERIOD.with(|period| {
+            let mut period = period.borrow_mut();
+            let epoch = period.epoch;
+            *period.readers.entry(epoch).or_default() += 1;
+            epoch
+        });
+        LocalGrace {
+            epoch,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Default for LocalGrace {
+    fn default() -> Self {
+        LocalGrace::new()
+    }
+}
+
+impl Clone for LocalGrace {
+    fn clone(&self) -> Self {
+        PERIOD.with(|period| *period.borrow_mut().readers.entry(self.epoch).or_default() += 1);
+        LocalGrace {
+            epoch: self.epoch,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for LocalGrace {
+    fn drop(&mut self) {
+        let last = PERIOD.with(|period| {
+            let mut period = period.borrow_mut();
+            let readers = period.readers.get_mut(&self.epoch).unwrap();
+            *readers -= 1;
+            if *readers > 0 {
+                return false;
+            }
+            period.readers.remove(&self.epoch);
+            true
+        });
+        if last {
+            reclaim();
+        }
+    }
+}
+
+/// A reference-counted RCU pointer for a single thread
+///
+/// Cloning a `LocalRcu` gives another pointer to the same place, so an
+/// update made through one clone is visible through all of them.
+pub struct LocalRcu<T> {
+    inner: Rc<Inner<T>>,
+}
+
+struct Inner<T> {
+    ptr: Cell<*mut T>,
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.ptr.get()) });
+    }
+}
+
+impl<T> Clone for LocalRcu<T> {
+    fn clone(&self) -> Self {
+        LocalRcu {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: RcuClone + 'static> LocalRcu<T> {
+    /// Allocate a new LocalRcu pointer
+    pub fn new(value: T) -> Self {
+        LocalRcu {
+            inner: Rc::new(Inner {
+                ptr: Cell::new(Box::into_raw(Box::new(value))),
+            }),
+        }
+    }
+    /// Read the pointer, with the given grace period
+    ///
+    /// This is just a pointer read, and the value will not be freed until
+    /// the grace period is over, no matter how we are updated.
+    pub fn read<'a, 'b: 'a>(&'b self, _grace: &'a LocalGrace) -> &'a T {
+        unsafe { &*self.inner.ptr.get() }
+    }
+    /// Modify the contents of the `LocalRcu`
+    ///
+    /// Your closure modifies a private copy of the value, which is then
+    /// published.  The old value will be retained until the last
+    /// `LocalGrace` that is open when we publish is dropped.
+    pub fn update(&self, f: impl FnOnce(&mut T)) {
+        let mut copy = self.read(&LocalGrace::new()).rcu_clone();
+        f(&mut copy);
+        let old = self.inner.ptr.replace(Box::into_raw(Box::new(copy)));
+        let old: Box<dyn Any> = unsafe { Box::from_raw(old) };
+        PERIOD.with(|period| {
+            let mut period = period.borrow_mut();
+            period.epoch += 1;
+            let epoch = period.epoch;
+            period.garbage.retire(epoch, [old]);
+        });
+        reclaim();
+    }
+}
@@ -0,0 +1,260 @@
+//! Rcu pointers to borrowed data, within a scope
+//!
+//! The [`Rcu`](super::Rcu) in our parent module can only hold `'static`
+//! data, since its old values are kept in a global list until the last
+//! grace that might read them is dropped.  Within [`scope`], each
+//! [`Domain`] keeps its own list instead, so its pointers can hold data
+//! borrowed from outside the scope, and every old value has been freed
+//! by the time the scope returns.
+//! ```
+//! use rcu_clean::graceful::{self, scoped::{Grace, Rcu}};
+//! let names = vec!["alice".to_string(), "bob".to_string()];
+//! graceful::scope(|domain| {
+//!     let current = Rcu::new(domain, &names[0]);
+//!     let reading = current.clone();
+//!     domain.spawn(move |domain| {
+//!         let grace = Grace::new(domain);
+//!         assert!(reading.read(&grace).len() > 2);
+//!     });
+//!     current.update(|name| *name = &names[1]);
+//! });
+//! ```
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{Scope, ScopedJoinHandle};
+
+use crate::epochs::EpochQueue;
+use crate::RcuClone;
+
+/// Run `f` with a new [`Domain`], within a [`std::thread::scope`]
+///
+/// As with `std::thread::scope`, every thread spawned in the scope is
+/// joined before we return.  Once they are, we free every old value that
+/// was retired in the domain, unless a [`Grace`] has escaped the scope.
+/// ```
+/// use rcu_clean::graceful::{self, scoped::{Grace, Rcu}};
+/// let mut total = 0;
+/// let numbers = [1, 2, 3];
+/// graceful::scope(|domain| {
+///     let v = Rcu::new(domain, &numbers[..1]);
+///     for n in 2..=3 {
+///         v.update(|v| *v = &numbers[..n]);
+///     }
+///     total = v.read(&Grace::new(domain)).iter().sum();
+/// });
+/// assert_eq!(6, total);
+/// ```
+pub fn scope<'env, F, R>(f: F) -> R
+where
+    F: for<'scope> FnOnce(&Domain<'scope, 'env>) -> R,
+{
+    let shared = Arc::new(Shared {
+        period: Mutex::new(Period {
+            epoch: 0,
+            readers: BTreeMap::new(),
+            garbage: EpochQueue::default(),
+        }),
+    });
+    let result = std::thread::scope(|threads| {
+        f(&Domain {
+            threads,
+            shared: shared.clone(),
+        })
+    });
+    shared.reclaim(shared.period.lock().unwrap());
+    result
+}
+
+/// A place for [`Rcu`] pointers to borrowed data, created by [`scope`]
+///
+/// Each domain has grace periods of its own, so a [`Grace`] only protects
+/// reads of pointers in the same domain.
+#[derive(Clone)]
+pub struct Domain<'scope, 'env: 'scope> {
+    threads: &'scope Scope<'scope, 'env>,
+    shared: Arc<Shared<'env>>,
+}
+
+impl<'scope, 'env> Domain<'scope, 'env> {
+    /// Spawn a thread that is joined before the scope returns
+    ///
+    /// This is [`Scope::spawn`], except that `f` is given the domain, so
+    /// that the thread can begin graces of its own.
+    pub fn spawn<F, R>(&self, f: F) -> ScopedJoinHandle<'scope, R>
+    where
+        F: FnOnce(&Domain<'scope, 'env>) -> R + Send + 'scope,
+        R: Send + 'scope,
+    {
+        let domain = self.clone();
+        self.threads.spawn(move || f(&domain))
+    }
+    /// The [`std::thread::Scope`] that this domain lives within
+    pub fn threads(&self) -> &'scope Scope<'scope, 'env> {
+        self.threads
+    }
+}
+
+/// An old value, which may be freed once the graces that could be reading
+/// it are dropped
+type Garbage<'env> = Box<dyn Send + Sync + 'env>;
+
+struct Shared<'env> {
+    period: Mutex<Period<'env>>,
+}
+
+/// The same bookkeeping as for `'static` pointers, but for just one domain
+struct Period<'env> {
+    epoch: u64,
+    // The number of open graces, by the epoch in which they began
+    readers: BTreeMap<u64, usize>,
+    garbage: EpochQueue<Garbage<'env>>,
+}
+
+impl<'env> Shared<'env> {
+    /// Free every retired value that no open grace could be reading
+    fn reclaim<'a>(&'a self, mut period: MutexGuard<'a, Period<'env>>) {
+        loop {
+            let Period {
+                readers, garbage, ..
+            } = &mut *period;
+            let expired = garbage.expired(readers);
+            if expired.is_empty() {
+                return;
+            }
+            // We free the values without holding the lock, since dropping
+            // them could run any code at all.
+            drop(period);
+            drop(expired);
+            period = self.period.lock().unwrap();
+        }
+    }
+}
+
+/// A grace period within a [`Domain`]
+///
+/// Just like [`graceful::Grace`](super::Grace), no value that could be read
+/// with this grace is freed until it is dropped.
+pub struct Grace<'env> {
+    epoch: u64,
+    shared: Arc<Shared<'env>>,
+}
+
+impl<'env> Grace<'env> {
+    /// Create a new grace period in `domain`
+    pub fn new(domain: &Domain<'_, 'env>) -> Self {
+        let mut period = domain.shared.period.lock().unwrap();
+        let epoch = period.epoch;
+        *period.readers.entry(epoch).or_default() += 1;
+        Grace {
+            epoch,
+            shared: domain.shared.clone(),
+        }
+    }
+}
+
+impl<'env> Clone for Grace<'env> {
+    fn clone(&self) -> Self {
+        let mut period = self.shared.period.lock().unwrap();
+        *period.readers.entry(self.epoch).or_default() += 1;
+        Grace {
+            epoch: self.epoch,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<'env> Drop for Grace<'env> {
+    fn drop(&mut self) {
+        let mut period = self.shared.period.lock().unwrap();
+        let readers = period.readers.get_mut(&self.epoch).unwrap();
+        *readers -= 1;
+        if *readers == 0 {
+            period.readers.remove(&self.epoch);
+            self.shared.reclaim(period);
+        }
+    }
+}
+
+/// An Rcu pointer within a [`Domain`], which may hold borrowed data
+///
+/// Cloning an `Rcu` gives another pointer to the same place, so an update
+/// made through one clone is visible through all of them.
+pub struct Rcu<'env, T> {
+    inner: Arc<Inner<'env, T>>,
+}
+
+struct Inner<'env, T> {
+    ptr: AtomicPtr<T>,
+    // Writers take turns, so that no update is lost.
+    writer: Mutex<()>,
+    shared: Arc<Shared<'env>>,
+    // We own a `Box<T>`, so we are `Send` and `Sync` under the same
+    // conditions it is.
+    _marker: PhantomData<Box<T>>,
+}
+
+impl<'env, T> Drop for Inner<'env, T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(*self.ptr.get_mut()) });
+    }
+}
+
+impl<'env, T> Clone for Rcu<'env, T> {
+    fn clone(&self) -> Self {
+        Rcu {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<'env, T: RcuClone + Send + Sync + 'env> Rcu<'env, T> {
+    /// Allocate a new Rcu pointer in `domain`
+    pub fn new(domain: &Domain<'_, 'env>, value: T) -> Self {
+        Rcu {
+            inner: Arc::new(Inner {
+                ptr: AtomicPtr::new(Box::into_raw(Box::new(value))),
+                writer: Mutex::new(()),
+                shared: domain.shared.clone(),
+                _marker: PhantomData,
+            }),
+        }
+    }
+    /// Read the pointer, with the given grace period
+    ///
+    /// # Panics
+    ///
+    /// This panics if `grace` is from a different domain, since it would not
+    /// keep our old values alive.
+    pub fn read<'a>(&'a self, grace: &'a Grace<'env>) -> &'a T {
+        assert!(
+            Arc::ptr_eq(&self.inner.shared, &grace.shared),
+            "grace is from a different domain"
+        );
+        unsafe { &*self.inner.ptr.load(Ordering::Acquire) }
+    }
+    /// Modify the contents of the `Rcu`
+    ///
+    /// As with [`graceful::Rcu::update`](super::Rcu::update), this modifies
+    /// a private copy of the value, and the old value is retained until the
+    /// last `Grace` that is open when we publish is dropped.  Simultaneous
+    /// updates to the same pointer take turns.
+    pub fn update(&self, f: impl FnOnce(&mut T)) {
+        let _writer = self.inner.writer.lock().unwrap_or_else(|e| e.into_inner());
+        // Only writers change the pointer, and we hold the writer lock, so
+        // the current value cannot be freed while we copy it.
+        let mut copy = unsafe { &*self.inner.ptr.load(Ordering::Acquire) }.rcu_clone();
+        f(&mut copy);
+        let new = Box::into_raw(Box::new(copy));
+        let mut period = self.inner.shared.period.lock().unwrap();
+        period.epoch += 1;
+        let old = self.inner.ptr.swap(new, Ordering::AcqRel);
+        let old: Garbage<'env> = unsafe { Box::from_raw(old) };
+        let epoch = period.epoch;
+        period.garbage.retire(epoch, [old]);
+        if period.readers.is_empty() {
+            self.inner.shared.reclaim(period);
+        }
+    }
+}
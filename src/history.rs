@@ -0,0 +1,70 @@
+//! Bounded histories of published values, for reverting bad updates
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::Version;
+
+/// The most recently published values, newest first
+pub(crate) struct History<T: ?Sized> {
+    limit: usize,
+    entries: VecDeque<Entry<T>>,
+}
+
+struct Entry<T: ?Sized> {
+    version: Version,
+    value: Arc<T>,
+    // The version this one republished, if it was published by a revert
+    restored: Option<Version>,
+}
+
+impl<T: ?Sized> History<T> {
+    pub(crate) fn new(limit: usize) -> Self {
+        History {
+            limit,
+            entries: VecDeque::with_capacity(limit),
+        }
+    }
+    pub(crate) fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.entries.truncate(limit);
+    }
+    pub(crate) fn record(&mut self, version: Version, value: Arc<T>) {
+        self.entries.push_front(Entry {
+            version,
+            value,
+            restored: None,
+        });
+        self.entries.truncate(self.limit);
+    }
+    /// Note that `version` was published by reverting to `restored`
+    pub(crate) fn restored(&mut self, version: Version, restored: Version) {
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.version == version) {
+            entry.restored = Some(restored);
+        }
+    }
+    pub(crate) fn entries(&self) -> Vec<(Version, Arc<T>)> {
+        self.entries
+            .iter()
+            .map(|e| (e.version, e.value.clone()))
+            .collect()
+    }
+    /// The value to republish in order to undo the newest one
+    ///
+    /// This is the value published before the newest one, unless the newest
+    /// was itself published by a revert, in which case we step back past the
+    /// value that it restored, so that repeated reverts keep going back.
+    pub(crate) fn previous(&self) -> Option<(Version, Arc<T>)> {
+        let mut index = 0;
+        while let Some(restored) = self.entries.get(index)?.restored {
+            index = self.entries.iter().position(|e| e.version == restored)?;
+        }
+        let entry = self.entries.get(index + 1)?;
+        Some((entry.version, entry.value.clone()))
+    }
+    pub(crate) fn get(&self, version: Version) -> Option<Arc<T>> {
+        self.entries
+            .iter()
+            .find(|e| e.version == version)
+            .map(|e| e.value.clone())
+    }
+}
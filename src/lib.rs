@@ -57,54 +57,164 @@
 //! probably be more accurate with "epoch" tracking, but I don't know
 //! that the complexity will be worthwhile.
 
-mod boxrcu;
+pub mod boxrcu;
 pub use crate::boxrcu::BoxRcu;
 
-mod rcrcu;
-pub use crate::rcrcu::RcRcu;
+pub mod rcrcu;
+pub use crate::rcrcu::{RcRcu, WeakRcRcu};
 
-mod arcrcu;
-pub use crate::arcrcu::ArcRcu;
+pub mod arcrcu;
+pub use crate::arcrcu::{ArcRcu, WeakArcRcu};
 
 pub mod graceful;
 
+mod epochs;
+mod history;
+mod mapped;
+pub use crate::mapped::MappedGuard;
+mod recycle;
+
+/// The version of the value held by an RCU pointer
+///
+/// Every pointer starts out at version zero, and each time a new value is
+/// published the version is incremented, so comparing versions tells you
+/// whether the value has changed since you last looked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version(u64);
+
+impl Version {
+    fn next(self) -> Version {
+        Version(self.0 + 1)
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        self.0.fmt(f)
+    }
+}
+
+/// The error returned when an optimistic update finds an unexpected version
+///
+/// This means that someone else published a new value since you read
+/// version `expected`, so your update was not applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Conflict {
+    /// The version the update was based on
+    pub expected: Version,
+    /// The version that was actually current
+    pub actual: Version,
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "expected version {} but found version {}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+/// How an RCU pointer makes the private copy that an update modifies
+///
+/// Every type that implements [Clone] gets this for free, and uses
+/// `clone` to make its copies.  A type that can be copied more cheaply
+/// than a deep clone, for instance by sharing the parts that an update
+/// will not touch, can implement `RcuClone` directly (in which case it
+/// must not also implement `Clone`).
+///
+/// ```
+/// use rcu_clean::{ArcRcu, RcuClone};
+/// use std::collections::HashMap;
+/// use std::sync::Arc;
+///
+/// /// A map whose copies share every shard until it is modified
+/// struct ShardedMap {
+///     shards: Vec<Arc<HashMap<u64, String>>>,
+/// }
+/// impl RcuClone for ShardedMap {
+///     fn rcu_clone(&self) -> Self {
+///         ShardedMap { shards: self.shards.clone() }
+///     }
+/// }
+/// impl ShardedMap {
+///     fn insert(&mut self, key: u64, value: String) {
+///         let n = self.shards.len() as u64;
+///         Arc::make_mut(&mut self.shards[(key % n) as usize]).insert(key, value);
+///     }
+/// }
+///
+/// let map = ArcRcu::new(ShardedMap {
+///     shards: (0..4).map(|_| Arc::new(HashMap::new())).collect(),
+/// });
+/// let old: &ShardedMap = &map;
+/// map.update().insert(1, "one".to_string());
+/// assert!(old.shards[1].is_empty());
+/// assert_eq!(map.shards[1][&1], "one");
+/// assert!(Arc::ptr_eq(&old.shards[0], &map.shards[0]));
+/// ```
+pub trait RcuClone: Sized {
+    /// Make a copy of `self` to be modified and then published
+    fn rcu_clone(&self) -> Self;
+    /// Make `self` a copy of `source`, reusing whatever it can
+    ///
+    /// This is used when an update recycles a retired value rather than
+    /// allocating a new one.  By default it just replaces `self` with
+    /// `source.rcu_clone()`.
+    fn rcu_clone_from(&mut self, source: &Self) {
+        *self = source.rcu_clone();
+    }
+}
+
+impl<T: Clone> RcuClone for T {
+    fn rcu_clone(&self) -> Self {
+        self.clone()
+    }
+    fn rcu_clone_from(&mut self, source: &Self) {
+        self.clone_from(source);
+    }
+}
+
 macro_rules! impl_stuff {
     ($t:ident) => {
-        impl<T: PartialEq> PartialEq for $t<T> {
+        impl<T: ?Sized + PartialEq> PartialEq for $t<T> {
             fn eq(&self, other: &$t<T>) -> bool {
                 &(**self) == &(**other)
             }
         }
-        impl<T: Eq> Eq for $t<T> {}
-        impl<T: PartialOrd> PartialOrd for $t<T> {
+        impl<T: ?Sized + Eq> Eq for $t<T> {}
+        impl<T: ?Sized + PartialOrd> PartialOrd for $t<T> {
             fn partial_cmp(&self, other: &$t<T>) -> Option<std::cmp::Ordering> {
                 (**self).partial_cmp(&**other)
             }
         }
-        impl<T: Ord> Ord for $t<T> {
+        impl<T: ?Sized + Ord> Ord for $t<T> {
             fn cmp(&self, other: &$t<T>) -> std::cmp::Ordering {
                 (**self).cmp(&**other)
             }
         }
-        impl<T: std::fmt::Debug> std::fmt::Debug for $t<T> {
+        impl<T: ?Sized + std::fmt::Debug> std::fmt::Debug for $t<T> {
             fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
                 (**self).fmt(f)
             }
         }
-        impl<T: std::fmt::Display> std::fmt::Display for $t<T> {
+        impl<T: ?Sized + std::fmt::Display> std::fmt::Display for $t<T> {
             fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
                 (**self).fmt(f)
             }
         }
-        #[cfg(serde)]
-        impl<T: serde::Serialize> serde::Serialize for $t<T> {
+        #[cfg(feature = "serde")]
+        impl<T: ?Sized + serde::Serialize> serde::Serialize for $t<T> {
             fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-                (*self).serialize(serializer)
+                (**self).serialize(serializer)
             }
         }
-        #[cfg(serde)]
-        impl<'de, T: Deserialize<'de>> Deserialize<'de> for $t<T> {
-            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[cfg(feature = "serde")]
+        impl<'de, T: crate::RcuClone + serde::Deserialize<'de>> serde::Deserialize<'de> for $t<T> {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
                 T::deserialize(deserializer).map(|v| $t::new(v))
             }
         }
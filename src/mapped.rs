@@ -0,0 +1,63 @@
+//! [MappedGuard], a guard for a component of a value being updated
+use std::ops::DerefMut;
+
+/// A guard for a component of a value being updated
+///
+/// This is created by the `Guard::map` of [ArcRcu](crate::ArcRcu),
+/// [RcRcu](crate::RcRcu) or [BoxRcu](crate::BoxRcu), and publishes the entire
+/// updated value when it is dropped.  Each of those modules names it for its
+/// own `Guard`, as in `rcu_clean::arcrcu::MappedGuard<'a, T, U>`.
+pub struct MappedGuard<G, U: ?Sized> {
+    // The value being updated lives on the heap, either in the private copy
+    // owned by `guard` or, for an update in place, in the pointer's own
+    // allocation, so it does not move when the guard does.
+    guard: G,
+    value: *mut U,
+}
+impl<G: DerefMut, U: ?Sized> MappedGuard<G, U> {
+    pub(crate) fn new(mut guard: G, f: impl FnOnce(&mut G::Target) -> &mut U) -> Self {
+        let value: *mut U = f(&mut *guard);
+        MappedGuard { guard, value }
+    }
+    pub(crate) fn filter_new(
+        mut guard: G,
+        f: impl FnOnce(&mut G::Target) -> Option<&mut U>,
+    ) -> Result<Self, G> {
+        match f(&mut *guard).map(|v| v as *mut U) {
+            Some(value) => Ok(MappedGuard { guard, value }),
+            None => Err(guard),
+        }
+    }
+    /// Make a guard for a component of this component
+    pub fn map<V: ?Sized, F: FnOnce(&mut U) -> &mut V>(mut orig: Self, f: F) -> MappedGuard<G, V> {
+        let value: *mut V = f(&mut *orig);
+        MappedGuard {
+            guard: orig.guard,
+            value,
+        }
+    }
+    /// Make a guard for an optional component of this component
+    pub fn filter_map<V: ?Sized, F: FnOnce(&mut U) -> Option<&mut V>>(
+        mut orig: Self,
+        f: F,
+    ) -> Result<MappedGuard<G, V>, Self> {
+        match f(&mut *orig).map(|v| v as *mut V) {
+            Some(value) => Ok(MappedGuard {
+                guard: orig.guard,
+                value,
+            }),
+            None => Err(orig),
+        }
+    }
+}
+impl<G, U: ?Sized> std::ops::Deref for MappedGuard<G, U> {
+    type Target = U;
+    fn deref(&self) -> &U {
+        unsafe { &*self.value }
+    }
+}
+impl<G, U: ?Sized> std::ops::DerefMut for MappedGuard<G, U> {
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe { &mut *self.value }
+    }
+}
@@ -1,6 +1,10 @@
-use std::cell::{Cell, UnsafeCell};
+//! [RcRcu], a reference counted RCU pointer, and its update guards
+use std::cell::{Cell, RefCell, UnsafeCell};
 use std::ptr::null_mut;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
+
+use crate::recycle::Recycler;
+use crate::{Conflict, RcuClone, Version};
 
 /// A reference counted pointer that allows interior mutability
 ///
@@ -19,11 +23,11 @@ use std::rc::Rc;
 /// assert_eq!(*x, 7); // but the pointer now points to the new value.
 /// assert_eq!(*z, 7); // but the cloned pointer also points to the new value.
 /// ```
-pub struct RcRcu<T> {
+pub struct RcRcu<T: ?Sized> {
     inner: Rc<Inner<T>>,
     have_borrowed: Cell<bool>,
 }
-impl<T: Clone> Clone for RcRcu<T> {
+impl<T: ?Sized> Clone for RcRcu<T> {
     fn clone(&self) -> Self {
         RcRcu {
             inner: self.inner.clone(),
@@ -31,72 +35,260 @@ impl<T: Clone> Clone for RcRcu<T> {
         }
     }
 }
-pub struct Inner<T> {
+pub(crate) struct Inner<T: ?Sized> {
     borrow_count: Cell<usize>,
     am_writing: Cell<bool>,
+    observers: RefCell<Vec<(usize, Observer<T>)>>,
+    next_observer: Cell<usize>,
+    notifying: Cell<bool>,
+    recycler: RefCell<Spares<T>>,
     list: List<T>,
 }
-pub struct List<T> {
-    value: UnsafeCell<T>,
+type Observer<T> = Rc<dyn Fn(&T, &T)>;
+// Retired list entries kept for reuse, or the reclaimer that gets them
+type Spares<T> = Recycler<Box<List<T>>, fn(Box<T>)>;
+// Each value is boxed so that `T` may be unsized while `List<T>` is not.
+pub(crate) struct List<T: ?Sized> {
+    value: UnsafeCell<Box<T>>,
+    version: Cell<Version>,
     next: Cell<*mut List<T>>,
 }
+impl<T: ?Sized> List<T> {
+    fn new(value: Box<T>) -> Self {
+        List {
+            value: UnsafeCell::new(value),
+            version: Cell::new(Version(0)),
+            next: Cell::new(null_mut()),
+        }
+    }
+    /// Free this entry, but not its value, which is returned
+    ///
+    /// This must only be called on an entry with no `next`.
+    fn into_value(self) -> Box<T> {
+        let list = std::mem::ManuallyDrop::new(self);
+        unsafe { std::ptr::read(list.value.get()) }
+    }
+}
 
-impl<T> std::ops::Deref for RcRcu<T> {
+impl<T: ?Sized> Inner<T> {
+    fn new(value: Box<T>) -> Self {
+        Inner {
+            borrow_count: Cell::new(0),
+            am_writing: Cell::new(false),
+            observers: RefCell::new(Vec::new()),
+            next_observer: Cell::new(0),
+            notifying: Cell::new(false),
+            recycler: RefCell::new(Recycler::new()),
+            list: List::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> std::ops::Deref for RcRcu<T> {
     type Target = T;
     fn deref(&self) -> &T {
-        let aleady_borrowed = self.have_borrowed.get();
-        if !aleady_borrowed {
-            self.inner
-                .borrow_count
-                .set(self.inner.borrow_count.get() + 1);
-            self.have_borrowed.set(true); // indicate we have borrowed this once.
-        }
-        if self.inner.list.next.get() == null_mut() {
-            unsafe { &*self.inner.list.value.get() }
-        } else {
-            unsafe { &*(*self.inner.list.next.get()).value.get() }
-        }
+        unsafe { &*self.borrow_list().value.get() }
     }
 }
-impl<T> std::borrow::Borrow<T> for RcRcu<T> {
+impl<T: ?Sized> std::borrow::Borrow<T> for RcRcu<T> {
     fn borrow(&self) -> &T {
-        &*self
+        self
     }
 }
-impl<T> Drop for List<T> {
+impl<T: ?Sized> Drop for List<T> {
     fn drop(&mut self) {
-        if self.next.get() != null_mut() {
+        if !self.next.get().is_null() {
             let _to_free = unsafe { Box::from_raw(self.next.get()) };
         }
     }
 }
-impl<'a, T: Clone> RcRcu<T> {
-    pub fn new(x: T) -> Self {
+impl<T: ?Sized> RcRcu<T> {
+    /// Allocate a new pointer holding an already boxed value
+    ///
+    /// This allows `T` to be unsized, such as a `str`, a slice or a trait
+    /// object.
+    /// ```
+    /// let x: rcu_clean::RcRcu<[usize]> = rcu_clean::RcRcu::from_box(vec![1, 2].into());
+    /// x.update_from(|v| v.iter().map(|x| x * 10).collect());
+    /// assert_eq!(&*x, &[10, 20]);
+    /// ```
+    pub fn from_box(x: Box<T>) -> Self {
         RcRcu {
             have_borrowed: Cell::new(false),
-            inner: Rc::new(Inner {
-                borrow_count: Cell::new(0),
-                am_writing: Cell::new(false),
-                list: List {
-                    value: UnsafeCell::new(x),
-                    next: Cell::new(null_mut()),
-                },
-            }),
+            inner: Rc::new(Inner::new(x)),
         }
     }
-    pub fn update(&'a self) -> Guard<'a, T> {
+    /// The list entry holding the newest value
+    fn newest(&self) -> &List<T> {
+        if self.inner.list.next.get().is_null() {
+            &self.inner.list
+        } else {
+            unsafe { &*self.inner.list.next.get() }
+        }
+    }
+    /// The list entry holding the newest value, noting that we have borrowed
+    fn borrow_list(&self) -> &List<T> {
+        let aleady_borrowed = self.have_borrowed.get();
+        if !aleady_borrowed {
+            self.inner
+                .borrow_count
+                .set(self.inner.borrow_count.get() + 1);
+            self.have_borrowed.set(true); // indicate we have borrowed this once.
+        }
+        self.newest()
+    }
+    /// The version of the current value
+    pub fn version(&self) -> Version {
+        self.newest().version.get()
+    }
+    /// Read the current value along with its version
+    /// ```
+    /// let x = rcu_clean::RcRcu::new(1);
+    /// let (value, version) = x.read_versioned();
+    /// *x.update() = 2;
+    /// assert_eq!(*value, 1);
+    /// assert!(x.version() > version);
+    /// ```
+    pub fn read_versioned(&self) -> (&T, Version) {
+        let list = self.borrow_list();
+        (unsafe { &*list.value.get() }, list.version.get())
+    }
+    /// Create a [WeakRcRcu] pointer to this value
+    ///
+    /// This is an associated function that needs to be used as
+    /// `RcRcu::downgrade(...)`, just like `Rc::downgrade`.
+    pub fn downgrade(this: &Self) -> WeakRcRcu<T> {
+        WeakRcRcu {
+            inner: Rc::downgrade(&this.inner),
+        }
+    }
+    /// Publish a new value computed from the current one
+    ///
+    /// Since your function creates an entirely new boxed value, this works
+    /// even when `T` is unsized and cannot be cloned.
+    pub fn update_from(&self, f: impl FnOnce(&T) -> Box<T>) {
+        let value = f(self);
         if self.inner.am_writing.get() {
             panic!("Cannont update an RcRcu twice simultaneously.");
         }
-        self.inner.am_writing.set(true);
-        Guard {
-            list: Some(List {
-                value: UnsafeCell::new((*(*self)).clone()),
-                next: self.inner.list.next.clone(),
-            }),
-            rc_guts: &self.inner,
+        let old: *const List<T> = self.newest();
+        self.publish(self.new_list(value));
+        self.notify(old);
+    }
+    /// Keep up to `spares` retired values for reuse by later updates
+    ///
+    /// Normally each value is dropped once `clean` shows that no one can be
+    /// reading it.  With spares, we instead keep a few of those values, so
+    /// that later updates can reuse their allocations rather than making new
+    /// ones.  The spares are not dropped until they are reused or we are.
+    pub fn with_spares(self, spares: usize) -> Self {
+        let extra = self.inner.recycler.borrow_mut().set_spares(spares);
+        drop(extra);
+        self
+    }
+    /// Hand each value we retire to `reclaimer`, rather than dropping it
+    ///
+    /// A reclaimer gets every retired value (once `clean` shows that no one
+    /// can be reading it), even if we were asked to keep spares, so it can
+    /// recycle them however it likes.
+    /// ```
+    /// thread_local!(static RECLAIMED: std::cell::Cell<usize> = Default::default());
+    /// let mut x = rcu_clean::RcRcu::new(1).with_reclaimer(|v: Box<usize>| {
+    ///     RECLAIMED.with(|r| r.set(r.get() + *v));
+    /// });
+    /// *x.update() = 2;
+    /// *x.update() = 3;
+    /// x.clean();
+    /// assert_eq!(RECLAIMED.with(|r| r.get()), 1 + 2);
+    /// ```
+    pub fn with_reclaimer(self, reclaimer: fn(Box<T>)) -> Self {
+        self.inner.recycler.borrow_mut().set_reclaimer(reclaimer);
+        self
+    }
+    /// A list entry for a new value, reusing a retired one if we have one
+    fn new_list(&self, value: Box<T>) -> Box<List<T>> {
+        let spare = self.inner.recycler.borrow_mut().take();
+        match spare {
+            Some(mut list) => {
+                *list.value.get_mut() = value;
+                list
+            }
+            None => Box::new(List::new(value)),
+        }
+    }
+    /// Call `callback` with the old and new values whenever a value is
+    /// published
+    ///
+    /// The callback is unregistered when the returned [Subscription] is
+    /// dropped.  If a callback publishes another update, it is not called
+    /// reentrantly: instead, once every callback has seen the first change,
+    /// they are all called again for the next one.
+    /// ```
+    /// use std::cell::Cell;
+    /// use std::rc::Rc;
+    /// let x = rcu_clean::RcRcu::new(1);
+    /// let seen = Rc::new(Cell::new(0));
+    /// let s = seen.clone();
+    /// let subscription = x.on_change(move |old, new| s.set(old * 10 + new));
+    /// *x.update() = 2;
+    /// assert_eq!(seen.get(), 12);
+    /// drop(subscription);
+    /// *x.update() = 3;
+    /// assert_eq!(seen.get(), 12);
+    /// ```
+    pub fn on_change(&self, callback: impl Fn(&T, &T) + 'static) -> Subscription<T> {
+        let id = self.inner.next_observer.get();
+        self.inner.next_observer.set(id + 1);
+        self.inner
+            .observers
+            .borrow_mut()
+            .push((id, Rc::new(callback)));
+        Subscription {
+            inner: Rc::downgrade(&self.inner),
+            id,
         }
     }
+    /// Tell the observers about each version published since `old`
+    fn notify(&self, old: *const List<T>) {
+        if self.inner.observers.borrow().is_empty() || self.inner.notifying.replace(true) {
+            // If we are already notifying, the outer call will get to our
+            // change once it is done with the current one.
+            return;
+        }
+        // Borrowing ensures no one can clean away the values we are passing
+        // to the observers.
+        self.inner
+            .borrow_count
+            .set(self.inner.borrow_count.get() + 1);
+        let _done = Notifying(&self.inner);
+        let mut old = old;
+        loop {
+            let new: *const List<T> = self.newest();
+            if std::ptr::eq(old, new) {
+                break;
+            }
+            // Observers may subscribe or unsubscribe while we are calling
+            // them, so we work from a copy of the list.
+            let observers: Vec<_> = self
+                .inner
+                .observers
+                .borrow()
+                .iter()
+                .map(|(_, o)| o.clone())
+                .collect();
+            for observer in observers {
+                unsafe { observer(&*(*old).value.get(), &*(*new).value.get()) };
+            }
+            old = new;
+        }
+    }
+    fn publish(&self, list: Box<List<T>>) -> Version {
+        let version = self.version().next();
+        list.version.set(version);
+        list.next.set(self.inner.list.next.get());
+        self.inner.list.next.set(Box::into_raw(list));
+        version
+    }
     pub fn clean(&mut self) {
         let aleady_borrowed = self.have_borrowed.get();
         if aleady_borrowed {
@@ -105,56 +297,331 @@ impl<'a, T: Clone> RcRcu<T> {
                 .set(self.inner.borrow_count.get() - 1);
             self.have_borrowed.set(false); // indicate we have no longer borrowed this.
         }
-        if self.inner.borrow_count.get() == 0 && self.inner.list.next.get() != null_mut() {
+        if self.inner.borrow_count.get() == 0 && !self.inner.list.next.get().is_null() {
             unsafe {
-                std::mem::swap(
-                    &mut *self.inner.list.value.get(),
-                    &mut *(*self.inner.list.next.get()).value.get(),
+                std::ptr::swap(
+                    self.inner.list.value.get(),
+                    (*self.inner.list.next.get()).value.get(),
                 );
-                let _to_free = Box::from_raw(self.inner.list.next.replace(null_mut()));
+                self.inner
+                    .list
+                    .version
+                    .swap(&(*self.inner.list.next.get()).version);
+                self.retire(self.inner.list.next.replace(null_mut()));
+            }
+        }
+    }
+    /// Recycle a list of entries that no one can be reading any more
+    fn retire(&self, mut list: *mut List<T>) {
+        while !list.is_null() {
+            let old = unsafe { Box::from_raw(list) };
+            list = old.next.replace(null_mut());
+            self.recycle(old);
+        }
+    }
+    /// Keep an entry that no one is reading for reuse, or reclaim it
+    fn recycle(&self, list: Box<List<T>>) {
+        let mut recycler = self.inner.recycler.borrow_mut();
+        if let Some(list) = recycler.keep(list) {
+            let reclaimer = recycler.reclaimer();
+            drop(recycler);
+            match reclaimer {
+                Some(reclaim) => reclaim((*list).into_value()),
+                None => drop(list),
             }
         }
     }
 }
+impl<'a, T: RcuClone> RcRcu<T> {
+    pub fn new(x: T) -> Self {
+        RcRcu::from_box(Box::new(x))
+    }
+    /// Allocate a new pointer to a value that refers to itself
+    ///
+    /// Like `Rc::new_cyclic`, this gives your closure a [WeakRcRcu] that
+    /// will point to the `RcRcu` being created, but cannot be upgraded until
+    /// the closure has returned.
+    pub fn new_cyclic(f: impl FnOnce(&WeakRcRcu<T>) -> T) -> Self {
+        RcRcu {
+            have_borrowed: Cell::new(false),
+            inner: Rc::new_cyclic(|weak| {
+                Inner::new(Box::new(f(&WeakRcRcu {
+                    inner: weak.clone(),
+                })))
+            }),
+        }
+    }
+    /// Obtain a guard for modifying the value
+    ///
+    /// The value is only copied when the guard is first dereferenced
+    /// mutably, and a guard that is never dereferenced mutably publishes
+    /// nothing when it is dropped.  The guard never modifies the value in
+    /// place, since a reference read through this same `&self` could be
+    /// alive while we write; see [RcRcu::update_mut] for that.
+    pub fn update(&'a self) -> Guard<'a, T> {
+        if self.inner.am_writing.get() {
+            panic!("Cannont update an RcRcu twice simultaneously.");
+        }
+        self.inner.am_writing.set(true);
+        Guard {
+            value: None,
+            rcu: self,
+            same: None,
+            in_place: false,
+            modified_in_place: false,
+        }
+    }
+    /// A list entry holding a copy of `value`, reusing a retired one if we can
+    fn copy(&self, value: &T) -> Box<List<T>> {
+        let spare = self.inner.recycler.borrow_mut().take();
+        match spare {
+            Some(mut list) => {
+                list.value.get_mut().rcu_clone_from(value);
+                list
+            }
+            None => Box::new(List::new(Box::new(value.rcu_clone()))),
+        }
+    }
+    /// Modify the value only if it is still at the `expected` version
+    ///
+    /// This allows optimistic concurrency: read the value along with its
+    /// version, compute what you want to change, and then apply the change
+    /// only if no one else has published a new value in the meantime.
+    /// ```
+    /// let x = rcu_clean::RcRcu::new(1);
+    /// let (_, version) = x.read_versioned();
+    /// assert!(x.update_if_version(version, |v| *v = 2).is_ok());
+    /// assert!(x.update_if_version(version, |v| *v = 3).is_err());
+    /// assert_eq!(*x, 2);
+    /// ```
+    pub fn update_if_version<R>(
+        &'a self,
+        expected: Version,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Result<R, Conflict> {
+        let mut guard = self.update();
+        let actual = self.version();
+        if actual != expected {
+            return Err(Conflict { expected, actual });
+        }
+        Ok(f(&mut guard))
+    }
+    /// Obtain a guard for modifying the value, in place if possible
+    ///
+    /// When this is the only `RcRcu` pointing to the value, no reader can
+    /// observe the old value, so (much like `Rc::make_mut`) the guard
+    /// modifies the value in place rather than copying it.  Otherwise this
+    /// behaves just like [RcRcu::update].  We need a `&mut self` to be
+    /// sure that no references have been handed out by this pointer.
+    /// ```
+    /// let mut x = rcu_clean::RcRcu::new(vec![0; 1000]);
+    /// x.update_mut()[0] = 1; // No copy of the vec is made.
+    /// let y = x.clone();
+    /// x.update_mut()[0] = 2; // Here we copy, since `y` could be read.
+    /// assert_eq!(y[0], 2);
+    /// ```
+    pub fn update_mut(&'a mut self) -> Guard<'a, T> {
+        let mut in_place = Rc::get_mut(&mut self.inner).is_some();
+        if in_place {
+            self.clean();
+            // A clone that borrowed and was then dropped still counts as a
+            // borrower, so `clean` may have left newer values in the list,
+            // which we must not hide by writing to the old one.
+            in_place = self.inner.list.next.get().is_null();
+        }
+        let mut guard = self.update();
+        guard.in_place = in_place;
+        guard
+    }
+    /// Obtain a guard that only publishes the value if it has changed
+    ///
+    /// When the guard is dropped, the modified copy is compared with the
+    /// current value, and if they are equal the copy is discarded, so readers
+    /// need not follow an extra pointer to reach an identical value.
+    /// ```
+    /// let x = rcu_clean::RcRcu::new(vec![1, 2]);
+    /// x.update_if_changed().sort();
+    /// assert_eq!(*x, vec![1, 2]);
+    /// ```
+    pub fn update_if_changed(&'a self) -> Guard<'a, T>
+    where
+        T: PartialEq,
+    {
+        let mut guard = self.update();
+        guard.same = Some(T::eq);
+        guard
+    }
+}
+
+/// Undoes the setup for notification, even if an observer panics
+struct Notifying<'a, T: ?Sized>(&'a Inner<T>);
+impl<'a, T: ?Sized> Drop for Notifying<'a, T> {
+    fn drop(&mut self) {
+        self.0.borrow_count.set(self.0.borrow_count.get() - 1);
+        self.0.notifying.set(false);
+    }
+}
+
+/// A callback registered with [RcRcu::on_change]
+///
+/// The callback is unregistered when this is dropped.
+#[must_use = "the callback is unregistered when the Subscription is dropped"]
+pub struct Subscription<T: ?Sized> {
+    inner: Weak<Inner<T>>,
+    id: usize,
+}
+impl<T: ?Sized> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.upgrade() {
+            inner
+                .observers
+                .borrow_mut()
+                .retain(|(id, _)| *id != self.id);
+        }
+    }
+}
 
-pub struct Guard<'a, T: Clone> {
-    list: Option<List<T>>,
-    rc_guts: &'a Inner<T>,
+/// A weak pointer to an [RcRcu]
+///
+/// This is created by [RcRcu::downgrade], and like `std::rc::Weak` it does
+/// not keep the value alive.  Upgrading gives an [RcRcu] that sees the
+/// latest value published through any of its clones.
+/// ```
+/// let x = rcu_clean::RcRcu::new(1);
+/// let weak = rcu_clean::RcRcu::downgrade(&x);
+/// *x.update() = 2;
+/// assert_eq!(*weak.upgrade().unwrap(), 2);
+/// drop(x);
+/// assert!(weak.upgrade().is_none());
+/// ```
+pub struct WeakRcRcu<T: ?Sized> {
+    inner: Weak<Inner<T>>,
+}
+impl<T: ?Sized> WeakRcRcu<T> {
+    /// Create a [WeakRcRcu] that points to nothing
+    pub fn new() -> Self {
+        WeakRcRcu { inner: Weak::new() }
+    }
+    /// Attempt to get an [RcRcu], if the value still exists
+    pub fn upgrade(&self) -> Option<RcRcu<T>> {
+        self.inner.upgrade().map(|inner| RcRcu {
+            inner,
+            have_borrowed: Cell::new(false),
+        })
+    }
+}
+impl<T: ?Sized> Clone for WeakRcRcu<T> {
+    fn clone(&self) -> Self {
+        WeakRcRcu {
+            inner: self.inner.clone(),
+        }
+    }
 }
-impl<'a, T: Clone> std::ops::Deref for Guard<'a, T> {
+impl<T: ?Sized> Default for WeakRcRcu<T> {
+    fn default() -> Self {
+        WeakRcRcu::new()
+    }
+}
+
+pub struct Guard<'a, T: RcuClone> {
+    value: Option<Box<List<T>>>,
+    rcu: &'a RcRcu<T>,
+    same: Option<fn(&T, &T) -> bool>,
+    in_place: bool,
+    modified_in_place: bool,
+}
+impl<'a, T: RcuClone> std::ops::Deref for Guard<'a, T> {
     type Target = T;
     fn deref(&self) -> &T {
-        if let Some(ref list) = self.list {
+        if let Some(ref list) = self.value {
             unsafe { &*list.value.get() }
         } else {
-            unreachable!()
+            self.rcu
         }
     }
 }
-impl<'a, T: Clone> std::ops::DerefMut for Guard<'a, T> {
+impl<'a, T: RcuClone> std::ops::DerefMut for Guard<'a, T> {
     fn deref_mut(&mut self) -> &mut T {
-        if let Some(ref list) = self.list {
-            unsafe { &mut *list.value.get() }
-        } else {
-            unreachable!()
+        if self.in_place {
+            // We have a unique pointer that has been cleaned, so the value
+            // is stored in place.
+            self.modified_in_place = true;
+            return unsafe { &mut *self.rcu.inner.list.value.get() };
         }
+        let rcu = self.rcu;
+        let list = self.value.get_or_insert_with(|| rcu.copy(rcu));
+        list.value.get_mut()
     }
 }
-impl<'a, T: Clone> Drop for Guard<'a, T> {
+impl<'a, T: RcuClone> Drop for Guard<'a, T> {
     fn drop(&mut self) {
-        let list = std::mem::replace(&mut self.list, None);
-        self.rc_guts
-            .list
-            .next
-            .set(Box::into_raw(Box::new(list.unwrap())));
-        self.rc_guts.am_writing.set(false);
+        let old: *const List<T> = self.rcu.newest();
+        if let Some(mut list) = self.value.take() {
+            let unchanged = match self.same {
+                Some(same) => same(list.value.get_mut(), self.rcu),
+                None => false,
+            };
+            if unchanged {
+                self.rcu.recycle(list);
+            } else {
+                self.rcu.publish(list);
+            }
+        }
+        if self.modified_in_place {
+            let version = &self.rcu.inner.list.version;
+            version.set(version.get().next());
+        }
+        self.rcu.inner.am_writing.set(false);
+        // We notify only once we are done writing, so that observers may
+        // make updates of their own.
+        self.rcu.notify(old);
     }
 }
 
+impl<'a, T: RcuClone> Guard<'a, T> {
+    /// Make a guard for a component of the value being updated
+    ///
+    /// This is an associated function that needs to be used as
+    /// `Guard::map(...)`, so that it does not interfere with methods of
+    /// `T`.  This has the same semantics as `std::cell::RefMut::map`.  The
+    /// entire updated value is published when the [MappedGuard] is dropped.
+    ///
+    /// ```
+    /// let x = rcu_clean::RcRcu::new((1, 2));
+    /// {
+    ///     let update = x.update();
+    ///     let mut second = rcu_clean::rcrcu::Guard::map(update, |v| &mut v.1);
+    ///     *second = 7;
+    ///     assert_eq!(*x, (1, 2));
+    /// }
+    /// assert_eq!(*x, (1, 7));
+    /// ```
+    pub fn map<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(orig: Self, f: F) -> MappedGuard<'a, T, U> {
+        MappedGuard::new(orig, f)
+    }
+    /// Make a guard for an optional component of the value being updated
+    ///
+    /// This has the same semantics as `std::cell::RefMut::filter_map`.  If
+    /// the closure returns `None`, the original guard is handed back.
+    pub fn filter_map<U: ?Sized, F: FnOnce(&mut T) -> Option<&mut U>>(
+        orig: Self,
+        f: F,
+    ) -> Result<MappedGuard<'a, T, U>, Self> {
+        MappedGuard::filter_new(orig, f)
+    }
+}
+
+/// A guard for a component of a value being updated
+///
+/// This is created by [Guard::map], and publishes the entire updated value
+/// when it is dropped.
+pub type MappedGuard<'a, T, U> = crate::MappedGuard<Guard<'a, T>, U>;
+
 #[cfg(test)]
 mod test {
     use super::RcRcu;
     #[derive(Debug, Clone)]
+    #[allow(dead_code)]
     struct Foo(usize);
     #[test]
     fn debug() {
@@ -0,0 +1,52 @@
+//! Pools of retired allocations, for reuse by later updates
+
+/// The retired allocations of an RCU pointer, and what to do with them
+///
+/// By default we keep no spares, so retired values are simply dropped.
+pub(crate) struct Recycler<S, R> {
+    spares: Vec<S>,
+    // The most retired allocations we keep around for reuse
+    limit: usize,
+    reclaimer: Option<R>,
+}
+
+impl<S, R: Clone> Recycler<S, R> {
+    pub(crate) fn new() -> Self {
+        Recycler {
+            spares: Vec::new(),
+            limit: 0,
+            reclaimer: None,
+        }
+    }
+    /// Keep up to `limit` retired allocations for reuse
+    ///
+    /// This hands back any spares beyond the new limit, so that the caller
+    /// can drop them once it is no longer holding us.
+    pub(crate) fn set_spares(&mut self, limit: usize) -> Vec<S> {
+        self.limit = limit;
+        self.spares.split_off(limit.min(self.spares.len()))
+    }
+    /// Hand every retired value to `reclaimer` rather than reusing it
+    pub(crate) fn set_reclaimer(&mut self, reclaimer: R) {
+        self.reclaimer = Some(reclaimer);
+    }
+    pub(crate) fn reclaimer(&self) -> Option<R> {
+        self.reclaimer.clone()
+    }
+    /// A retired allocation to reuse, if we have one
+    pub(crate) fn take(&mut self) -> Option<S> {
+        self.spares.pop()
+    }
+    /// Keep a retired allocation for reuse, or hand it back if we cannot
+    ///
+    /// We hand it back when we already have enough spares, or when there is
+    /// a reclaimer that should get it instead.
+    pub(crate) fn keep(&mut self, spare: S) -> Option<S> {
+        if self.reclaimer.is_some() || self.spares.len() >= self.limit {
+            Some(spare)
+        } else {
+            self.spares.push(spare);
+            None
+        }
+    }
+}
@@ -0,0 +1,846 @@
+use rcu_clean::graceful::{Grace, Rcu};
+
+#[test]
+fn snapshot_outlives_grace() {
+    let v = Rcu::new(vec![1, 2, 3]);
+    let snapshot = {
+        let grace = Grace::new();
+        v.read(&grace).to_arc()
+    };
+    for i in 4..100 {
+        v.update(|v| v.push(i));
+    }
+    let handle = std::thread::spawn(move || snapshot.iter().sum::<i32>());
+    assert_eq!(6, handle.join().unwrap());
+    assert_eq!(99, v.load_full().len());
+}
+
+#[test]
+fn clone_while_updating() {
+    let v = Rcu::new(0usize);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for i in 1..1000 {
+                v.update(|v| *v = i);
+            }
+        });
+        for _ in 0..1000 {
+            let c = v.clone();
+            assert!(*c.load_full() < 1000);
+        }
+    });
+}
+
+#[test]
+fn map_read_guard() {
+    use rcu_clean::graceful::{MappedRcuGuard, RcuGuard};
+    let v = Rcu::new((1, vec![2, 3]));
+    let grace = Grace::new();
+    let second = RcuGuard::map(v.read(&grace), |v| &v.1);
+    let missing = RcuGuard::filter_map(v.read(&grace), |v| v.1.get(5));
+    assert!(missing.is_err());
+    v.update(|v| v.1.clear());
+    let last = MappedRcuGuard::filter_map(second, |v| v.last())
+        .ok()
+        .unwrap();
+    assert_eq!(3, *last);
+    assert!(v.read(&grace).1.is_empty());
+}
+
+#[test]
+fn write_guard_aborts_on_panic() {
+    let v = Rcu::new(vec![1, 2, 3]);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let mut w = v.write();
+        w.push(4);
+        panic!("oops");
+    }));
+    assert!(result.is_err());
+    assert_eq!(3, v.load_full().len());
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        v.update(|v| {
+            v.clear();
+            panic!("oops");
+        })
+    }));
+    assert!(result.is_err());
+    assert_eq!(3, v.load_full().len());
+    v.write().push(4);
+    assert_eq!(4, v.load_full().len());
+}
+
+#[test]
+fn clones_are_independent() {
+    use std::sync::Arc;
+    let v = Rcu::new(vec![1]);
+    v.keep_history(10);
+    v.update(|v| v.push(2));
+    let w = v.clone();
+    assert_eq!(v.version(), w.version());
+    assert!(Arc::ptr_eq(&v.load_full(), &w.load_full()));
+    v.update(|v| v.push(3));
+    w.update(|w| w.clear());
+    assert_eq!(vec![1, 2, 3], *v.load_full());
+    assert!(w.load_full().is_empty());
+    // Our history stays with the original.
+    assert_eq!(None, w.revert());
+    assert!(v.revert().is_some());
+}
+
+#[test]
+fn shared_pointers_share_updates() {
+    let v = Rcu::new(1);
+    let w = v.share();
+    let weak = Rcu::downgrade(&v);
+    v.update(|v| *v = 2);
+    assert_eq!(2, *w.load_full());
+    drop(v);
+    w.update(|v| *v = 3);
+    assert_eq!(3, *weak.upgrade().unwrap().load_full());
+    drop(w);
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn unsized_values() {
+    let s: Rcu<str> = Rcu::from_box("hello".into());
+    let grace = Grace::new();
+    let old = s.read(&grace);
+    s.update_from(|s| format!("{} world", s).into());
+    assert_eq!("hello", &*old);
+    assert_eq!("hello world", &*s.load_full());
+
+    let v: Rcu<[u8]> = Rcu::from(std::sync::Arc::from(&b"abc"[..]));
+    v.update_from(|v| v.iter().map(|c| c.to_ascii_uppercase()).collect());
+    assert_eq!(b"ABC", &*v.read(&grace));
+}
+
+#[test]
+fn versions() {
+    let mut v = Rcu::new(vec![1]);
+    let v0 = v.version();
+    {
+        let grace = Grace::new();
+        let old = v.read(&grace);
+        v.update(|v| v.push(2));
+        assert_eq!(old.version(), v0);
+        assert!(v.read(&grace).version() > v0);
+    }
+    let v1 = v.version();
+    let conflict = v.update_if_version(v0, |v| v.push(3)).unwrap_err();
+    assert_eq!(conflict.actual, v1);
+    assert_eq!(2, v.load_full().len());
+    assert_eq!(Ok(Some(2)), v.update_if_version(v1, |v| v.pop()));
+    let v2 = v.version();
+    assert!(v2 > v1);
+    v.update_mut(|v| v.push(4));
+    assert!(v.version() > v2);
+}
+
+/// A minimal executor, so that we need not depend on an async runtime
+fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    struct Unpark(std::thread::Thread);
+    impl std::task::Wake for Unpark {
+        fn wake(self: std::sync::Arc<Self>) {
+            self.0.unpark()
+        }
+    }
+    let waker = std::sync::Arc::new(Unpark(std::thread::current())).into();
+    let mut cx = std::task::Context::from_waker(&waker);
+    let mut f = Box::pin(f);
+    loop {
+        match f.as_mut().poll(&mut cx) {
+            std::task::Poll::Ready(x) => return x,
+            std::task::Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+#[test]
+fn watch_updates() {
+    let v = Rcu::new(0usize);
+    let mut watcher = v.subscribe();
+    let writer = {
+        let v = v.share();
+        std::thread::spawn(move || {
+            for i in 1..=1000 {
+                v.update(|v| *v = i);
+            }
+        })
+    };
+    let mut last = (watcher.version(), 0);
+    while last.1 < 1000 {
+        let (version, value) = block_on(watcher.next_version()).unwrap();
+        assert!(version > last.0);
+        assert!(*value > last.1);
+        last = (version, *value);
+    }
+    writer.join().unwrap();
+    drop(v);
+    assert!(block_on(watcher.changed()).is_none());
+}
+
+#[test]
+fn watch_wakes_every_task() {
+    let v = Rcu::new(0usize);
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let mut watcher = v.subscribe();
+            std::thread::spawn(move || block_on(watcher.changed()).map(|v| *v))
+        })
+        .collect();
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    v.update(|v| *v = 1);
+    for r in readers {
+        assert_eq!(Some(1), r.join().unwrap());
+    }
+}
+
+#[test]
+fn derived_computed_once_per_version() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    let computed = Arc::new(AtomicUsize::new(0));
+    let v = Rcu::new(vec![1, 2, 3]);
+    let c = computed.clone();
+    let sum = v.derive(move |v| {
+        c.fetch_add(1, Ordering::Relaxed);
+        v.iter().sum::<i32>()
+    });
+    let doubled = sum.derive(|s| s * 2);
+    assert_eq!(1, computed.load(Ordering::Relaxed));
+    v.update(|v| v.push(4));
+    v.update(|v| v.push(5));
+    assert_eq!(1, computed.load(Ordering::Relaxed)); // computed lazily
+    std::thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| {
+                let grace = Grace::new();
+                assert_eq!(15, *sum.read(&grace));
+                assert_eq!(30, *doubled.read(&grace));
+            });
+        }
+    });
+    assert_eq!(2, computed.load(Ordering::Relaxed));
+    assert_eq!(v.version(), doubled.version());
+
+    // Reading only the end of a chain recomputes the whole chain.
+    let grace = Grace::new();
+    let old = doubled.read(&grace);
+    v.update(|v| v.clear());
+    assert_eq!(0, *doubled.read(&grace));
+    assert_eq!(30, *old);
+    assert_eq!(0, *sum.load_full());
+    assert_eq!(3, computed.load(Ordering::Relaxed));
+}
+
+#[test]
+fn transactions_are_atomic() {
+    use rcu_clean::graceful::transaction;
+    let a = Rcu::new(0usize);
+    let b = Rcu::new(0usize);
+    let done = std::sync::atomic::AtomicBool::new(false);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for _ in 0..1000 {
+                transaction(|tx| {
+                    tx.update(&a, |a| *a += 1);
+                    tx.update(&b, |b| *b += 1);
+                    tx.update(&a, |a| *a += 1);
+                });
+                // A plain update on top of a transaction must not let old
+                // graces peek at it.
+                b.update(|b| *b += 1);
+            }
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+        let held = Grace::new();
+        let first = (*a.read(&held), *b.read(&held));
+        while !done.load(std::sync::atomic::Ordering::Relaxed) {
+            let grace = Grace::new();
+            let (x, y) = (*b.read(&grace), *a.read(&grace));
+            // After each transaction `b` is one behind `a`, until it catches
+            // up with the following plain update.
+            assert!(y == x || y == x + 1, "{} {}", x, y);
+            assert_eq!(first, (*a.read(&held), *b.read(&held)));
+        }
+    });
+    assert_eq!((2000, 2000), (*a.load_full(), *b.load_full()));
+}
+
+#[test]
+fn transaction_aborts_on_panic() {
+    use rcu_clean::graceful::transaction;
+    let a = Rcu::new(1);
+    let b = Rcu::new(1);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        transaction(|tx| {
+            tx.update(&a, |a| *a = 2);
+            tx.update(&b, |_| panic!("oops"));
+        })
+    }));
+    assert!(result.is_err());
+    assert_eq!((1, 1), (*a.load_full(), *b.load_full()));
+    let v = transaction(|tx| {
+        tx.update(&a, |a| *a = 3);
+        7
+    });
+    assert_eq!((7, 3), (v, *a.load_full()));
+}
+
+#[test]
+fn derived_under_old_grace() {
+    use rcu_clean::graceful::transaction;
+    let a = Rcu::new(1);
+    let doubled = a.derive(|a| a * 2);
+    let old = Grace::new();
+    transaction(|tx| tx.update(&a, |a| *a = 2));
+    assert_eq!(4, *doubled.load_full());
+    assert_eq!(2, *doubled.read(&old));
+    assert_eq!(4, *doubled.load_full());
+}
+
+#[test]
+fn derived_under_snapshot() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    let computed = Arc::new(AtomicUsize::new(0));
+    let a = Rcu::new(1);
+    let c = computed.clone();
+    let d = a.derive(move |v| {
+        c.fetch_add(1, Ordering::SeqCst);
+        v * 10
+    });
+    assert_eq!(10, *d.read(&Grace::new()));
+    a.update(|v| *v = 2);
+    let s = Grace::snapshot();
+    assert_eq!(2, *a.read(&s));
+    assert_eq!(20, *d.read(&s));
+    assert_eq!(20, *d.read(&s));
+    assert_eq!(20, *d.load_full());
+    assert_eq!(2, computed.load(Ordering::SeqCst));
+    // Later changes are hidden from the snapshot, derived or not.
+    a.update(|v| *v = 3);
+    assert_eq!((2, 20), (*a.read(&s), *d.read(&s)));
+    assert_eq!(30, *d.load_full());
+}
+
+#[test]
+fn snapshot_sees_consistent_derived_chain() {
+    let a = Rcu::new(1);
+    let doubled = a.derive(|a| a * 2);
+    let quadrupled = doubled.derive(|d| d * 2);
+    assert_eq!(4, *quadrupled.load_full());
+    a.update(|a| *a = 2);
+    let s = Grace::snapshot();
+    a.update(|a| *a = 3);
+    assert_eq!(12, *quadrupled.load_full());
+    for _ in 0..2 {
+        let read = (*a.read(&s), *doubled.read(&s), *quadrupled.read(&s));
+        assert_eq!((2, 4, 8), read);
+    }
+}
+
+#[test]
+fn derived_computed_once_for_old_grace() {
+    use rcu_clean::graceful::transaction;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    let computed = Arc::new(AtomicUsize::new(0));
+    let a = Rcu::new(1);
+    let c = computed.clone();
+    let doubled = a.derive(move |a| {
+        c.fetch_add(1, Ordering::Relaxed);
+        a * 2
+    });
+    let old = Grace::new();
+    transaction(|tx| tx.update(&a, |a| *a = 2));
+    assert_eq!(4, *doubled.load_full());
+    assert_eq!(2, computed.load(Ordering::Relaxed));
+    let first = doubled.read(&old);
+    for _ in 0..1000 {
+        assert_eq!(2, *doubled.read(&old));
+    }
+    assert_eq!(3, computed.load(Ordering::Relaxed));
+    assert_eq!(2, *first);
+}
+
+#[test]
+fn snapshots_are_consistent() {
+    let a = Rcu::new(0usize);
+    let b = Rcu::new(0usize);
+    let done = std::sync::atomic::AtomicBool::new(false);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for _ in 0..1000 {
+                a.update(|a| *a += 1);
+                b.update(|b| *b += 1);
+            }
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+        while !done.load(std::sync::atomic::Ordering::Relaxed) {
+            let snapshot = Grace::snapshot();
+            let y = *b.read(&snapshot);
+            std::thread::yield_now();
+            let x = *a.read(&snapshot);
+            assert!(x == y || x == y + 1, "{} {}", x, y);
+            assert_eq!(y, *b.read(&snapshot.clone()));
+        }
+    });
+}
+
+#[test]
+fn update_mut_respects_snapshots() {
+    let mut v = Rcu::new(1);
+    let snapshot = Grace::snapshot();
+    v.update_mut(|v| *v = 2);
+    assert_eq!(1, *v.read(&snapshot));
+    drop(snapshot);
+    let grace = Grace::new();
+    assert_eq!(2, *v.read(&grace));
+}
+
+#[test]
+fn snapshot_waits_for_update_mut() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    let mut v = Rcu::new(1);
+    let done = AtomicBool::new(false);
+    let (started, wait_for_start) = std::sync::mpsc::channel();
+    let snapshot = std::thread::scope(|s| {
+        s.spawn(|| {
+            v.update_mut(|v| {
+                started.send(()).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                *v = 2;
+                done.store(true, Ordering::SeqCst);
+            })
+        });
+        wait_for_start.recv().unwrap();
+        let snapshot = Grace::snapshot();
+        (done.load(Ordering::SeqCst), snapshot)
+    });
+    // Unless another test's snapshot made us copy, our snapshot waited for
+    // the modification, and either way it must not see a change that was
+    // made after it began.
+    let (done, snapshot) = snapshot;
+    assert!(done || *v.read(&snapshot) == 1);
+}
+
+#[test]
+fn history_and_revert() {
+    let v: Rcu<str> = Rcu::from_box("a".into());
+    assert_eq!(None, v.revert());
+    v.keep_history(2);
+    let a = v.version();
+    v.update_from(|_| "b".into());
+    v.update_from(|_| "c".into());
+    assert_eq!(None, v.revert_to(a));
+    let b = v.history().nth(1).unwrap().0;
+    let reverted = v.revert_to(b).unwrap();
+    assert_eq!("b", &*v.load_full());
+    assert_eq!(reverted, v.version());
+    // Reverting again would go back to before "b", which we have forgotten.
+    assert_eq!(None, v.revert());
+    assert_eq!("b", &*v.load_full());
+    let grace = Grace::new();
+    let old = v.read(&grace);
+    v.keep_history(0);
+    v.update_from(|_| "d".into());
+    assert_eq!("b", &*old);
+}
+
+#[test]
+fn repeated_revert_steps_back() {
+    let config = Rcu::new("first");
+    config.keep_history(10);
+    config.update(|c| *c = "good");
+    config.update(|c| *c = "bad");
+    config.update(|c| *c = "worse");
+    assert!(config.revert().is_some());
+    assert_eq!("bad", *config.load_full());
+    assert!(config.revert().is_some());
+    assert_eq!("good", *config.load_full());
+    config.update(|c| *c = "new");
+    // A new update can be undone, and then we pick up where we left off.
+    config.revert();
+    assert_eq!("good", *config.load_full());
+    config.revert();
+    assert_eq!("first", *config.load_full());
+    assert_eq!(None, config.revert());
+}
+
+#[test]
+fn enqueued_updates_are_combined() {
+    let v = Rcu::new(Vec::new());
+    v.keep_history(100_000);
+    let versions: Vec<_> = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let v = &v;
+                s.spawn(move || {
+                    (0..100)
+                        .map(|i| (t * 100 + i, v.enqueue_update(move |v| v.push(t * 100 + i))))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .map(|(i, h)| (i, h.wait().unwrap()))
+            .collect()
+    });
+    assert_eq!(800, v.load_full().len());
+    // Each update is in the version its handle resolved to, but not the one
+    // before.
+    let history: std::collections::BTreeMap<_, _> = v.history().collect();
+    for (i, version) in versions {
+        assert!(history[&version].contains(&i));
+        let before = history.range(..version).next_back().unwrap().1;
+        assert!(!before.contains(&i));
+    }
+}
+
+#[test]
+fn enqueued_update_panics() {
+    let v = Rcu::new(0);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        v.enqueue_update(|_| panic!("oops"))
+    }));
+    assert!(result.is_err());
+    let done = v.enqueue_update(|v| *v += 1);
+    assert!(done.is_finished());
+    assert!(done.wait().is_some());
+    assert_eq!(1, *v.load_full());
+}
+
+#[test]
+fn custom_rcu_clone() {
+    use std::sync::Arc;
+    struct Shared(Arc<Vec<usize>>, usize);
+    impl rcu_clean::RcuClone for Shared {
+        fn rcu_clone(&self) -> Self {
+            Shared(self.0.clone(), self.1)
+        }
+    }
+    let v = Rcu::new(Shared(Arc::new(vec![1, 2, 3]), 0));
+    let grace = Grace::new();
+    let old = v.read(&grace);
+    v.update(|s| s.1 += 1);
+    let new = v.load_full();
+    assert_eq!((old.1, new.1), (0, 1));
+    assert!(Arc::ptr_eq(&old.0, &new.0));
+}
+
+#[test]
+fn retired_values_are_reclaimed() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static RECLAIMED: AtomicUsize = AtomicUsize::new(0);
+    let v = Rcu::new(1).with_reclaimer(|v| {
+        RECLAIMED.fetch_add(*v, Ordering::SeqCst);
+    });
+    let grace = Grace::new();
+    v.update(|v| *v = 2);
+    v.keep_history(1);
+    let kept = v.read(&grace).to_arc();
+    v.update(|v| *v = 3);
+    v.update(|v| *v = 4);
+    assert_eq!(0, RECLAIMED.load(Ordering::SeqCst));
+    drop(grace);
+    // Other tests may be holding graces of their own, which also delay
+    // reclaiming our values.
+    let start = std::time::Instant::now();
+    while RECLAIMED.load(Ordering::SeqCst) < 1 + 3 {
+        assert!(start.elapsed().as_secs() < 10);
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    // The value we held onto was not retired.
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    assert_eq!(1 + 3, RECLAIMED.load(Ordering::SeqCst));
+    assert_eq!(2, *kept);
+}
+
+#[test]
+fn ending_grace_drops_old_values() {
+    use std::sync::Arc;
+    let marker = Arc::new(());
+    let v = Rcu::new((0, marker.clone()));
+    let grace = Grace::new();
+    v.update(|v| v.0 = 1);
+    v.update(|v| v.0 = 2);
+    assert_eq!(4, Arc::strong_count(&marker));
+    drop(grace);
+    // Other tests may be holding graces of their own, which also delay
+    // freeing our values.
+    let start = std::time::Instant::now();
+    while Arc::strong_count(&marker) > 2 {
+        assert!(start.elapsed().as_secs() < 10);
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn reclaimer_thread() {
+    use rcu_clean::graceful::spawn_reclaimer;
+    use std::sync::Mutex;
+    static RECLAIMED_ON: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    let reclaimer = spawn_reclaimer();
+    let second = spawn_reclaimer();
+    let v = Rcu::new(0).with_reclaimer(|_| {
+        let thread = std::thread::current();
+        RECLAIMED_ON
+            .lock()
+            .unwrap()
+            .push(thread.name().unwrap_or("").to_string());
+    });
+    let grace = Grace::new();
+    for i in 1..=10 {
+        v.update(|v| *v = i);
+    }
+    drop(second);
+    drop(grace);
+    // Other tests may be holding graces of their own, which also delay
+    // reclaiming our values.
+    let start = std::time::Instant::now();
+    loop {
+        reclaimer.flush();
+        if RECLAIMED_ON.lock().unwrap().len() == 10 {
+            break;
+        }
+        assert!(start.elapsed().as_secs() < 10);
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    assert!(RECLAIMED_ON
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|t| t == "rcu-reclaimer"));
+    drop(reclaimer);
+    v.update(|v| *v = 11);
+    assert_eq!(11, *v.load_full());
+}
+
+#[test]
+fn reclaimer_thread_drops_values() {
+    use rcu_clean::graceful::spawn_reclaimer;
+    use std::sync::Mutex;
+    static DROPPED_ON: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    #[derive(Clone)]
+    struct Noisy(usize);
+    impl Drop for Noisy {
+        fn drop(&mut self) {
+            let thread = std::thread::current();
+            DROPPED_ON
+                .lock()
+                .unwrap()
+                .push(thread.name().unwrap_or("").to_string());
+        }
+    }
+    let reclaimer = spawn_reclaimer();
+    let v = Rcu::new(Noisy(0));
+    let grace = Grace::new();
+    for i in 1..=10 {
+        v.update(|v| v.0 = i);
+    }
+    assert!(DROPPED_ON.lock().unwrap().is_empty());
+    drop(grace);
+    // Other tests may be holding graces of their own, which also delay
+    // dropping our values.
+    let start = std::time::Instant::now();
+    loop {
+        reclaimer.flush();
+        if DROPPED_ON.lock().unwrap().len() == 10 {
+            break;
+        }
+        assert!(start.elapsed().as_secs() < 10);
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    assert!(DROPPED_ON
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|t| t == "rcu-reclaimer"));
+    drop(reclaimer);
+}
+
+#[test]
+fn million_updates_under_one_grace() {
+    let v = Rcu::new(0usize);
+    let grace = Grace::new();
+    let first = v.read(&grace);
+    for i in 1..=1_000_000 {
+        v.update(|v| *v = i);
+    }
+    assert_eq!(0, *first);
+    // Dropping the grace frees a million old values, which must not
+    // overflow the stack.
+    drop(grace);
+    assert_eq!(1_000_000, *v.load_full());
+}
+
+#[test]
+fn refresh_releases_old_values() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static RECLAIMED: AtomicUsize = AtomicUsize::new(0);
+    let v = Rcu::new(1).with_reclaimer(|v| {
+        RECLAIMED.fetch_add(*v, Ordering::SeqCst);
+    });
+    let mut grace = Grace::new();
+    v.update(|v| *v = 2);
+    v.update(|v| *v = 3);
+    assert_eq!(3, *v.read(&grace));
+    assert_eq!(0, RECLAIMED.load(Ordering::SeqCst));
+    grace.refresh();
+    let start = std::time::Instant::now();
+    while RECLAIMED.load(Ordering::SeqCst) < 1 + 2 {
+        assert!(start.elapsed().as_secs() < 10);
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    // Our refreshed grace still holds back what is retired now.
+    v.update(|v| *v = 4);
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    assert_eq!(1 + 2, RECLAIMED.load(Ordering::SeqCst));
+    assert_eq!(4, *v.read(&grace));
+}
+
+#[test]
+fn nested_with_grace() {
+    use rcu_clean::graceful::with_grace;
+    let v = Rcu::new(1);
+    with_grace(|outer| {
+        let first = v.read(outer);
+        v.update(|v| *v = 2);
+        with_grace(|inner| {
+            assert!(std::ptr::eq(outer, inner));
+            assert_eq!(2, *v.read(inner));
+        });
+        assert_eq!(1, *first);
+    });
+    // A panic does not leave a dangling grace behind.
+    let result = std::panic::catch_unwind(|| with_grace(|_| panic!("oops")));
+    assert!(result.is_err());
+    with_grace(|grace| assert_eq!(2, *v.read(grace)));
+}
+
+#[test]
+fn async_grace_across_await() {
+    use rcu_clean::graceful::AsyncGrace;
+    let v = Rcu::new(vec![1, 2, 3]);
+    let task = {
+        let v = v.share();
+        let mut watcher = v.subscribe();
+        let mut grace = AsyncGrace::new();
+        let seen = v.read(&grace).version();
+        async move {
+            watcher.changed().await;
+            grace.resume();
+            let stale = grace.revalidate(&v, seen).is_err();
+            (stale, v.read(&grace).len())
+        }
+    };
+    let handle = std::thread::spawn(move || block_on(task));
+    v.update(|v| v.push(4));
+    assert_eq!((true, 4), handle.join().unwrap());
+}
+
+#[test]
+fn scoped_rcu_holds_borrowed_data() {
+    use rcu_clean::graceful::scoped::{self, Rcu};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    /// Counts how many of its copies have been dropped
+    struct Counted<'a>(&'a AtomicUsize, usize);
+    impl Clone for Counted<'_> {
+        fn clone(&self) -> Self {
+            Counted(self.0, self.1)
+        }
+    }
+    impl Drop for Counted<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    let dropped = AtomicUsize::new(0);
+    rcu_clean::graceful::scope(|domain| {
+        let v = Rcu::new(domain, Counted(&dropped, 0));
+        let grace = scoped::Grace::new(domain);
+        let first = v.read(&grace);
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let v = v.clone();
+                domain.spawn(move |domain| {
+                    let mut last = 0;
+                    while last < 100 {
+                        let grace = scoped::Grace::new(domain);
+                        let now = v.read(&grace).1;
+                        assert!(now >= last);
+                        last = now;
+                    }
+                })
+            })
+            .collect();
+        for i in 1..=100 {
+            v.update(|v| v.1 = i);
+        }
+        for r in readers {
+            r.join().unwrap();
+        }
+        // Our grace is still holding onto the first value.
+        assert_eq!(0, first.1);
+        assert_eq!(100, v.read(&grace).1);
+        // No old value is dropped until our grace is.
+        assert_eq!(0, dropped.load(Ordering::SeqCst));
+        drop(grace);
+        assert_eq!(100, dropped.load(Ordering::SeqCst));
+        v.update(|v| v.1 = 0);
+        let held = scoped::Grace::new(domain);
+        v.update(|v| v.1 = 1);
+        assert_eq!(101, dropped.load(Ordering::SeqCst));
+        // A grace in another thread keeps its values alive until the scope
+        // has joined that thread.
+        domain.spawn(move |_| drop(held));
+    });
+    assert_eq!(103, dropped.load(Ordering::SeqCst));
+}
+
+#[test]
+fn local_rcu_frees_after_grace() {
+    use rcu_clean::graceful::local::{LocalGrace, LocalRcu};
+    use std::cell::Cell;
+    use std::rc::Rc;
+    /// Counts how many of its copies have been dropped
+    #[derive(Clone)]
+    struct Counted(Rc<Cell<usize>>, usize);
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+    let dropped = Rc::new(Cell::new(0));
+    let v = LocalRcu::new(Counted(dropped.clone(), 0));
+    let w = v.clone();
+    let grace = LocalGrace::new();
+    let first = v.read(&grace);
+    // More updates than we free in one batch
+    for i in 1..=100 {
+        w.update(|v| v.1 = i);
+    }
+    let later = LocalGrace::new();
+    let hundredth = v.read(&later);
+    w.update(|v| v.1 = 101);
+    assert_eq!((0, 100, 101), (first.1, hundredth.1, v.read(&later).1));
+    assert_eq!(0, dropped.get());
+    drop(grace);
+    // The later grace still holds onto the value it read.
+    assert_eq!(100, dropped.get());
+    drop(later);
+    assert_eq!(101, dropped.get());
+    // Without any graces, updates free the old value right away, even when
+    // they are nested.
+    v.update(|outer| {
+        w.update(|inner| inner.1 = 1000);
+        outer.1 += 1;
+    });
+    assert_eq!(102, v.read(&LocalGrace::new()).1);
+    drop((v, w));
+    assert_eq!(104, dropped.get());
+}
@@ -0,0 +1,102 @@
+//! These tests change global limits, so they live in their own process.
+use rcu_clean::graceful::{on_stall, set_budget, Backpressure, Grace, Rcu, StallWarning};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[test]
+fn stalls_and_budgets() {
+    let warnings: Arc<Mutex<Vec<StallWarning>>> = Arc::default();
+    let w = warnings.clone();
+    on_stall(
+        Some(Duration::from_millis(50)),
+        Some(1000),
+        move |warning| {
+            w.lock().unwrap().push(StallWarning {
+                backtrace: warning.backtrace.clone(),
+                ..*warning
+            })
+        },
+    );
+    let v = Rcu::new(vec![0u8; 100]).with_weigher(|v| v.len());
+    let grace = Grace::new();
+    for i in 0..20 {
+        v.update(|v| v[0] = i);
+    }
+    {
+        let warnings = warnings.lock().unwrap();
+        assert_eq!(1, warnings.len());
+        assert_eq!(11, warnings[0].retained_values);
+        assert_eq!(1100, warnings[0].retained_bytes);
+        assert_eq!(
+            cfg!(feature = "grace-backtrace"),
+            warnings[0].backtrace.is_some()
+        );
+    }
+    drop(grace);
+    v.update(|v| v[0] = 0);
+    assert_eq!(1, warnings.lock().unwrap().len());
+
+    // A grace that has been open too long is also a stall.
+    let grace = Grace::new();
+    v.update(|v| v[0] = 1);
+    std::thread::sleep(Duration::from_millis(60));
+    v.update(|v| v[0] = 2);
+    assert_eq!(2, warnings.lock().unwrap().len());
+    assert!(warnings.lock().unwrap()[1].open >= Duration::from_millis(50));
+    drop(grace);
+
+    set_budget(Some(250), Backpressure::Block);
+    let grace = Grace::new();
+    for i in 0..3 {
+        v.update(|v| v[0] = i);
+    }
+    let err = v.try_update(|v| v[0] = 3).unwrap_err();
+    assert_eq!((300, 250), (err.retained_bytes, err.budget));
+    std::thread::scope(|s| {
+        let blocked = s.spawn(|| v.update(|v| v[0] = 4));
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(!blocked.is_finished());
+        drop(grace);
+        blocked.join().unwrap();
+    });
+    assert_eq!(4, v.load_full()[0]);
+
+    set_budget(Some(250), Backpressure::Fail);
+    let grace = Grace::new();
+    for i in 0..3 {
+        v.update(|v| v[0] = i);
+    }
+    let panic = std::panic::catch_unwind(|| v.update(|v| v[0] = 5));
+    assert!(panic.is_err());
+    drop(grace);
+    v.update(|v| v[0] = 5);
+    assert_eq!(5, v.load_full()[0]);
+
+    // A guard that was taken within the budget publishes when dropped.
+    let mut w = v.write();
+    w[0] = 6;
+    let grace = Grace::new();
+    for i in 0..3 {
+        v.update(|v| v[0] = i);
+    }
+    drop(w);
+    assert_eq!(6, v.load_full()[0]);
+    let panic = std::panic::catch_unwind(|| v.enqueue_update(|v| v[0] = 7));
+    assert!(panic.is_err());
+    drop(grace);
+    assert_eq!(6, v.load_full()[0]);
+    set_budget(None, Backpressure::Fail);
+
+    // Derived values are measured by their own weigher.
+    let n = Rcu::new(1usize);
+    let zeros = n.derive(|n| vec![0u8; 1000 * n]).with_weigher(|z| z.len());
+    let grace = Grace::new();
+    assert_eq!(1000, zeros.read(&grace).len());
+    n.update(|n| *n = 2);
+    assert_eq!(2000, zeros.read(&Grace::new()).len());
+    set_budget(Some(0), Backpressure::Block);
+    let err = v.try_update(|v| v[0] = 8).unwrap_err();
+    assert_eq!(std::mem::size_of::<usize>() + 1000, err.retained_bytes);
+    drop(grace);
+    set_budget(None, Backpressure::Fail);
+}
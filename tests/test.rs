@@ -44,3 +44,402 @@ testrc!(arcrcu, ArcRcu);
 testany!(boxrcu_any, BoxRcu);
 testany!(rcrcu_any, RcRcu);
 testany!(arcrcu_any, ArcRcu);
+
+macro_rules! testmap {
+    ($name:ident, $t:ident, $m:ident) => {
+        #[test]
+        fn $name() {
+            let ptr = $t::new((4, vec![1, 2]));
+            {
+                let guard = ptr.update();
+                let mut v = rcu_clean::$m::Guard::map(guard, |x| &mut x.1);
+                v.push(3);
+                assert_eq!(ptr.1.len(), 2);
+                let mut last = rcu_clean::$m::MappedGuard::map(v, |v| v.last_mut().unwrap());
+                *last = 5;
+                assert_eq!(ptr.1.len(), 2);
+            }
+            assert_eq!(*ptr, (4, vec![1, 2, 5]));
+            let guard = ptr.update();
+            let guard = match rcu_clean::$m::Guard::filter_map(guard, |x| x.1.get_mut(7)) {
+                Ok(_) => panic!("there is no eighth element"),
+                Err(guard) => guard,
+            };
+            let mut first =
+                rcu_clean::$m::Guard::filter_map(guard, |x| x.1.first_mut()).ok().unwrap();
+            *first = 0;
+            drop(first);
+            assert_eq!(*ptr, (4, vec![0, 2, 5]));
+        }
+    };
+}
+
+testmap!(boxrcu_map, BoxRcu, boxrcu);
+testmap!(rcrcu_map, RcRcu, rcrcu);
+testmap!(arcrcu_map, ArcRcu, arcrcu);
+
+thread_local! {
+    static CLONES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[derive(Debug, PartialEq)]
+struct Counted(usize);
+impl Clone for Counted {
+    fn clone(&self) -> Self {
+        CLONES.with(|c| c.set(c.get() + 1));
+        Counted(self.0)
+    }
+}
+fn clones() -> usize {
+    CLONES.with(|c| c.get())
+}
+
+macro_rules! testlazy {
+    ($name:ident, $t:ident) => {
+        #[test]
+        fn $name() {
+            let ptr = $t::new(Counted(1));
+            let before = clones();
+            {
+                let guard = ptr.update();
+                assert_eq!(guard.0, 1);
+            }
+            assert_eq!(clones(), before);
+            ptr.update().0 = 2;
+            assert_eq!(clones(), before + 1);
+            assert_eq!(ptr.0, 2);
+            {
+                let mut guard = ptr.update_if_changed();
+                guard.0 = 3;
+                guard.0 = 2;
+            }
+            assert_eq!(ptr.0, 2);
+            ptr.update_if_changed().0 = 4;
+            assert_eq!(ptr.0, 4);
+        }
+    };
+}
+
+testlazy!(boxrcu_lazy, BoxRcu);
+testlazy!(rcrcu_lazy, RcRcu);
+testlazy!(arcrcu_lazy, ArcRcu);
+
+macro_rules! testclean {
+    ($name:ident, $t:ident) => {
+        #[test]
+        fn $name() {
+            let mut ptr = $t::new(vec![0]);
+            for i in 1..10 {
+                ptr.update().push(i);
+            }
+            ptr.clean();
+            ptr.clean();
+            assert_eq!(ptr.len(), 10);
+            ptr.update().push(10);
+            ptr.clean();
+            assert_eq!(*ptr, (0..11).collect::<Vec<_>>());
+        }
+    };
+}
+
+testclean!(boxrcu_clean, BoxRcu);
+testclean!(rcrcu_clean, RcRcu);
+testclean!(arcrcu_clean, ArcRcu);
+
+macro_rules! testinplace {
+    ($name:ident, $t:ident) => {
+        #[test]
+        fn $name() {
+            let mut ptr = $t::new(Counted(1));
+            let before = clones();
+            ptr.update_mut().0 = 2;
+            assert_eq!(clones(), before);
+            assert_eq!(ptr.0, 2);
+            ptr.update().0 = 3;
+            ptr.update_mut().0 = 4;
+            assert_eq!(clones(), before + 1);
+            assert_eq!(ptr.0, 4);
+        }
+    };
+}
+
+testinplace!(boxrcu_in_place, BoxRcu);
+testinplace!(rcrcu_in_place, RcRcu);
+testinplace!(arcrcu_in_place, ArcRcu);
+
+macro_rules! testinplaceafterclone {
+    ($name:ident, $t:ident) => {
+        #[test]
+        fn $name() {
+            let mut x = $t::new(vec![0]);
+            let y = x.clone();
+            assert_eq!(y[0], 0);
+            x.update()[0] = 1;
+            drop(y);
+            x.update_mut()[0] = 2;
+            assert_eq!(x[0], 2);
+        }
+    };
+}
+
+testinplaceafterclone!(rcrcu_in_place_after_clone, RcRcu);
+testinplaceafterclone!(arcrcu_in_place_after_clone, ArcRcu);
+
+#[test]
+fn arcrcu_shared_not_in_place() {
+    let mut ptr = ArcRcu::new(Counted(1));
+    let other = ptr.clone();
+    let old: &Counted = &other;
+    let before = clones();
+    ptr.update_mut().0 = 2;
+    assert_eq!(clones(), before + 1);
+    assert_eq!(old.0, 1);
+    assert_eq!(other.0, 2);
+}
+
+macro_rules! testweak {
+    ($name:ident, $t:ident, $weak:ident) => {
+        #[test]
+        fn $name() {
+            #[derive(Clone)]
+            struct Node {
+                parent: $t<Option<usize>>,
+                me: rcu_clean::$weak<Node>,
+            }
+            let parent = $t::new(None);
+            let node = $t::new_cyclic(|me| Node {
+                parent: parent.clone(),
+                me: me.clone(),
+            });
+            let me = node.me.upgrade().unwrap();
+            assert!(me.parent.is_none());
+            *parent.update() = Some(1);
+            assert_eq!(*me.parent, Some(1));
+            let weak = $t::downgrade(&node);
+            drop(me);
+            drop(node);
+            assert!(weak.upgrade().is_none());
+        }
+    };
+}
+
+testweak!(rcrcu_weak, RcRcu, WeakRcRcu);
+testweak!(arcrcu_weak, ArcRcu, WeakArcRcu);
+
+macro_rules! testunsized {
+    ($name:ident, $t:ident) => {
+        #[test]
+        fn $name() {
+            let s: $t<str> = $t::from_box("hello".into());
+            let old: &str = &s;
+            s.update_from(|s| format!("{} world", s).into());
+            assert_eq!(old, "hello");
+            assert_eq!(&*s, "hello world");
+            assert_eq!(format!("{:?}", s), "\"hello world\"");
+
+            let v: $t<[usize]> = $t::from_box(vec![1, 2, 3].into());
+            v.update_from(|v| v.iter().rev().cloned().collect());
+            assert_eq!(&*v, &[3, 2, 1]);
+
+            let d: $t<dyn std::fmt::Display> = $t::from_box(Box::new(1));
+            d.update_from(|d| Box::new(format!("{}!", d)));
+            assert_eq!(d.to_string(), "1!");
+        }
+    };
+}
+
+testunsized!(boxrcu_unsized, BoxRcu);
+testunsized!(rcrcu_unsized, RcRcu);
+testunsized!(arcrcu_unsized, ArcRcu);
+
+macro_rules! testversion {
+    ($name:ident, $t:ident) => {
+        #[test]
+        fn $name() {
+            let mut ptr = $t::new(vec![1]);
+            let (value, v0) = ptr.read_versioned();
+            assert_eq!(value, &vec![1]);
+            ptr.update().len(); // only reading does not publish
+            assert_eq!(ptr.version(), v0);
+            ptr.update().push(2);
+            let v1 = ptr.version();
+            assert!(v1 > v0);
+            let err = ptr.update_if_version(v0, |v| v.push(3)).unwrap_err();
+            assert_eq!(err, rcu_clean::Conflict { expected: v0, actual: v1 });
+            assert_eq!(*ptr, vec![1, 2]);
+            assert_eq!(ptr.update_if_version(v1, |v| v.pop()), Ok(Some(2)));
+            let v2 = ptr.version();
+            assert!(v2 > v1);
+            ptr.clean();
+            assert_eq!(ptr.version(), v2);
+            ptr.update_mut().push(4);
+            assert!(ptr.version() > v2);
+            assert_eq!(*ptr, vec![1, 4]);
+        }
+    };
+}
+
+testversion!(boxrcu_version, BoxRcu);
+testversion!(rcrcu_version, RcRcu);
+testversion!(arcrcu_version, ArcRcu);
+
+#[test]
+fn arcrcu_wait_for_update() {
+    use std::time::Duration;
+    let ptr = ArcRcu::new(0usize);
+    std::thread::scope(|s| {
+        let waiters: Vec<_> = (0..4)
+            .map(|_| {
+                let mine = ptr.clone();
+                s.spawn(move || {
+                    let (mut value, mut seen) = mine.read_versioned();
+                    while *value < 100 {
+                        seen = mine.wait_for_update(seen);
+                        value = &*mine;
+                    }
+                    *mine.wait_until(|v| *v == 100)
+                })
+            })
+            .collect();
+        for i in 1..=100 {
+            *ptr.update() = i;
+        }
+        for w in waiters {
+            assert_eq!(w.join().unwrap(), 100);
+        }
+    });
+    let short = Duration::from_millis(10);
+    assert!(ptr.wait_until_timeout(|v| *v > 100, short).is_none());
+    assert_eq!(ptr.wait_until_timeout(|v| *v == 100, short), Some(&100));
+    assert_eq!(ptr.wait_for_update_timeout(ptr.version(), short), None);
+}
+
+#[test]
+fn rcrcu_on_change() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    let ptr = RcRcu::new(0);
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let l = log.clone();
+    let p = RcRcu::downgrade(&ptr);
+    let _bump = ptr.on_change(move |old, new| {
+        l.borrow_mut().push((*old, *new));
+        // An observer that publishes is not called reentrantly.
+        if *new < 3 {
+            *p.upgrade().unwrap().update() += 1;
+        }
+    });
+    let l = log.clone();
+    let second = ptr.on_change(move |_, new| l.borrow_mut().push((-1, *new)));
+    *ptr.update() = 1;
+    assert_eq!(*ptr, 3);
+    assert_eq!(
+        *log.borrow(),
+        vec![(0, 1), (-1, 1), (1, 2), (-1, 2), (2, 3), (-1, 3)]
+    );
+    drop(second);
+    log.borrow_mut().clear();
+    ptr.update_if_changed().clone_from(&3);
+    ptr.update_from(|v| Box::new(v + 1));
+    assert_eq!(*log.borrow(), vec![(3, 4)]);
+}
+
+#[test]
+fn arcrcu_history() {
+    let mut ptr = ArcRcu::new(vec![0]);
+    assert_eq!(ptr.history().count(), 0);
+    assert_eq!(ptr.revert(), None);
+    ptr.keep_history(3);
+    let v0 = ptr.version();
+    ptr.update().push(1);
+    ptr.update_mut().push(2);
+    let newest: Vec<_> = ptr.history().map(|(_, v)| v.len()).collect();
+    assert_eq!(newest, vec![3, 2, 1]);
+    assert!(ptr.revert().unwrap() > v0);
+    assert_eq!(*ptr, vec![0, 1]);
+    assert_eq!(ptr.revert_to(v0), None);
+    // A second revert keeps stepping back, rather than undoing the first.
+    ptr.keep_history(10);
+    ptr.update().push(3);
+    ptr.update().push(4);
+    assert!(ptr.revert().is_some());
+    assert!(ptr.revert().is_some());
+    assert_eq!(*ptr, vec![0, 1]);
+    // Before that was vec![0], which has already fallen out of history.
+    assert_eq!(ptr.revert(), None);
+    assert_eq!(*ptr, vec![0, 1]);
+    ptr.keep_history(0);
+    assert_eq!(ptr.history().count(), 0);
+}
+
+#[derive(Debug, PartialEq)]
+struct Shared(std::sync::Arc<Vec<usize>>, usize);
+impl rcu_clean::RcuClone for Shared {
+    fn rcu_clone(&self) -> Self {
+        Shared(self.0.clone(), self.1)
+    }
+}
+
+macro_rules! testrcuclone {
+    ($name:ident, $t:ident) => {
+        #[test]
+        fn $name() {
+            let ptr = $t::new(Shared(std::sync::Arc::new(vec![1, 2, 3]), 0));
+            let old: &Shared = &ptr;
+            ptr.update().1 = 1;
+            assert_eq!(old.1, 0);
+            assert_eq!(ptr.1, 1);
+            assert!(std::sync::Arc::ptr_eq(&old.0, &ptr.0));
+        }
+    };
+}
+
+testrcuclone!(boxrcu_rcu_clone, BoxRcu);
+testrcuclone!(rcrcu_rcu_clone, RcRcu);
+testrcuclone!(arcrcu_rcu_clone, ArcRcu);
+
+macro_rules! testrecycle {
+    ($name:ident, $t:ident) => {
+        #[test]
+        fn $name() {
+            thread_local!(static RECLAIMED: std::cell::RefCell<Vec<usize>> = Default::default());
+            let mut ptr = $t::new(vec![0usize; 100]).with_spares(2);
+            let buffer = ptr.as_ptr();
+            ptr.update()[0] = 1;
+            assert_ne!(ptr.as_ptr(), buffer);
+            ptr.clean();
+            // The next copy reuses the value we just retired.
+            ptr.update()[0] = 2;
+            assert_eq!(ptr.as_ptr(), buffer);
+
+            let mut ptr = ptr.with_reclaimer(|v| RECLAIMED.with(|r| r.borrow_mut().push(v[0])));
+            ptr.update()[0] = 3;
+            ptr.update_if_changed()[0] = 3;
+            ptr.clean();
+            assert_eq!(RECLAIMED.with(|r| r.take()), vec![3, 1, 2]);
+            assert_eq!(ptr[0], 3);
+        }
+    };
+}
+
+testrecycle!(rcrcu_recycle, RcRcu);
+testrecycle!(arcrcu_recycle, ArcRcu);
+
+macro_rules! testcleandrops {
+    ($name:ident, $t:ident) => {
+        #[test]
+        fn $name() {
+            let marker = std::sync::Arc::new(());
+            let mut ptr = $t::new((0, marker.clone()));
+            ptr.update().0 = 1;
+            ptr.update().0 = 2;
+            assert_eq!(std::sync::Arc::strong_count(&marker), 4);
+            // Without spares, clean frees every old value.
+            ptr.clean();
+            assert_eq!(std::sync::Arc::strong_count(&marker), 2);
+        }
+    };
+}
+
+testcleandrops!(rcrcu_clean_drops, RcRcu);
+testcleandrops!(arcrcu_clean_drops, ArcRcu);